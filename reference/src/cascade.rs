@@ -0,0 +1,362 @@
+//! The settings of a set of cascades, and merging them on the CPU.
+
+use super::*;
+
+/// Where the interval of each level ends. The first level always starts at `base_interval.0`,
+/// and every other level starts where the previous one ended.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum IntervalSchedule {
+    /// `base_interval.1 * length_scaling^level`.
+    #[default]
+    Geometric,
+    /// The end of every level, in order.
+    Ends(Vec<f32>),
+    /// The ratio between the end of each level and the previous, starting from level 1.
+    /// Levels past the end of the list use `length_scaling`.
+    Scaling(Vec<f32>),
+}
+impl IntervalSchedule {
+    /// Where the interval of `level` ends, given the end of the first level and the scaling used past the
+    /// end of a list. Any level has an end, including those past the last cascade, which the parallax
+    /// merge uses as the target of the top level.
+    pub fn end(&self, base_end: f32, length_scaling: f32, level: u32) -> f32 {
+        match self {
+            Self::Geometric => base_end * length_scaling.powi(level as i32),
+            Self::Ends(ends) => match ends.get(level as usize) {
+                Some(&end) => end,
+                None => {
+                    let last = ends.last().copied().unwrap_or(base_end);
+                    last * length_scaling.powi(level as i32 + 1 - ends.len() as i32)
+                }
+            },
+            Self::Scaling(scaling) => (1..=level as usize)
+                .map(|l| scaling.get(l - 1).copied().unwrap_or(length_scaling))
+                .fold(base_end, |end, factor| end * factor),
+        }
+    }
+}
+
+/// How a level gathers the radiance of the level above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeVariant {
+    /// One randomly chosen probe of the bilinear neighbours, weighted by its bilinear weight.
+    #[default]
+    Stochastic,
+    /// The nearest of the bilinear neighbours.
+    Nearest,
+    /// All four bilinear neighbours, each with its own ray (4x slower).
+    BilinearFix,
+    /// Two diagonally opposite bilinear neighbours (2x slower).
+    Diagonal,
+    /// All four bilinear neighbours like `BilinearFix`, but each looks towards where the ray
+    /// leaves the next interval instead of parallel to it, which reduces ringing (4x slower).
+    Parallax,
+}
+impl MergeVariant {
+    pub const ALL: [Self; 5] = [
+        Self::Stochastic,
+        Self::Nearest,
+        Self::BilinearFix,
+        Self::Diagonal,
+        Self::Parallax,
+    ];
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Stochastic => "stochastic",
+            Self::Nearest => "nearest",
+            Self::BilinearFix => "bilinear_fix",
+            Self::Diagonal => "diagonal",
+            Self::Parallax => "parallax",
+        }
+    }
+}
+impl std::fmt::Display for MergeVariant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The layout of the probes and rays of every level, and their intervals.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CascadeSettings {
+    pub base_interval: (f32, f32),
+    pub base_probe_spacing: f32,
+    /// The number of probes of the first level.
+    pub base_probes: UVec2,
+    /// The number of facings of the first level, before averaging the branches.
+    pub base_facings: u32,
+    pub num_cascades: u32,
+    pub spatial_factor: u32,
+    pub angular_factor: u32,
+    pub length_scaling: f32,
+    pub intervals: IntervalSchedule,
+    pub noise: NoiseSource,
+}
+impl CascadeSettings {
+    pub fn spacing(&self) -> u32 {
+        1 << self.spatial_factor
+    }
+    pub fn branches(&self) -> u32 {
+        1 << self.angular_factor
+    }
+    pub fn facing_count(&self, level: u32) -> u32 {
+        self.base_facings << (level * self.angular_factor)
+    }
+    /// Rounded up, so that the last probes of a level still cover the edge of the world.
+    pub fn probe_count(&self, level: u32) -> UVec2 {
+        let spacing = 1 << (level * self.spatial_factor);
+        UVec2::new(
+            self.base_probes.x.div_ceil(spacing),
+            self.base_probes.y.div_ceil(spacing),
+        )
+    }
+    pub fn facing_direction(&self, facing: u32, level: u32) -> FVec2 {
+        let angle = (facing as f32 + 0.5_f32) / self.facing_count(level) as f32 * TAU;
+        FVec2::new(angle.cos(), angle.sin())
+    }
+    pub fn probe_location(&self, probe: UVec2, level: u32) -> FVec2 {
+        (probe.as_vec2() + 0.5) * self.probe_spacing(level)
+    }
+    pub fn probe_spacing(&self, level: u32) -> f32 {
+        self.base_probe_spacing * (1 << (self.spatial_factor * level)) as f32
+    }
+    pub fn interval_end(&self, level: u32) -> f32 {
+        self.intervals
+            .end(self.base_interval.1, self.length_scaling, level)
+    }
+    pub fn interval(&self, level: u32) -> FVec2 {
+        FVec2::new(
+            if level == 0 {
+                self.base_interval.0
+            } else {
+                self.interval_end(level - 1)
+            },
+            self.interval_end(level),
+        )
+    }
+    /// Returns the base index and the fractional offset of the bilinear samples.
+    pub fn bilinear_samples(&self, probe: UVec2, next_level: u32) -> (UVec2, FVec2) {
+        let next_level_probe_location = ((probe.as_vec2() + 0.5) / self.spacing() as f32) - 0.5;
+        let probe_count = self.probe_count(next_level);
+        let next_level_probe_location =
+            next_level_probe_location.clamp(FVec2::ZERO, probe_count.as_vec2() - 1.0);
+        // Keep the second sample inside the level at the far edges, using a weight of 1 instead.
+        let base_index = next_level_probe_location
+            .floor()
+            .as_uvec2()
+            .min(probe_count.max(UVec2::splat(2)) - 2);
+        let fract = next_level_probe_location - base_index.as_vec2();
+        (base_index, fract)
+    }
+}
+
+fn bilinear_sample(base_index: UVec2, fract: FVec2, index: u32) -> (UVec2, f32) {
+    let weights = [
+        (1.0 - fract.x) * (1.0 - fract.y),
+        fract.x * (1.0 - fract.y),
+        (1.0 - fract.x) * fract.y,
+        fract.x * fract.y,
+    ];
+    let indices = [
+        UVec2::new(0, 0),
+        UVec2::new(1, 0),
+        UVec2::new(0, 1),
+        UVec2::new(1, 1),
+    ];
+    (
+        indices[index as usize] + base_index,
+        weights[index as usize],
+    )
+}
+
+fn diagonal_sample(base_index: UVec2, fract: FVec2, index: u32) -> (UVec2, f32) {
+    let a = (fract.x + fract.y) / 2.0;
+    let b = (1.0 - fract.x + fract.y) / 2.0;
+    let flip = (fract.x > 0.5) == (fract.y > 0.5);
+    let weights = [b, 1.0 - b, 1.0 - a, a];
+    let indices = [
+        UVec2::new(0, 0),
+        UVec2::new(1, 1),
+        UVec2::new(1, 0),
+        UVec2::new(0, 1),
+    ];
+    let i = (index + 2 * flip as u32) as usize;
+    (indices[i] + base_index, weights[i])
+}
+
+/// The radiance of every level of a set of cascades, pre-averaged over the branches like on the GPU.
+pub struct Cascades {
+    settings: CascadeSettings,
+    radiance: Vec<Vec<FVec3>>,
+}
+impl Cascades {
+    pub fn new(settings: CascadeSettings) -> Self {
+        let radiance = (0..settings.num_cascades)
+            .map(|level| {
+                let probes = settings.probe_count(level);
+                vec![
+                    FVec3::ZERO;
+                    (probes.x * probes.y * settings.facing_count(level) / settings.branches())
+                        as usize
+                ]
+            })
+            .collect();
+        Self { settings, radiance }
+    }
+    pub fn settings(&self) -> &CascadeSettings {
+        &self.settings
+    }
+    fn storage_facings(&self, level: u32) -> u32 {
+        self.settings.facing_count(level) / self.settings.branches()
+    }
+    pub fn read(&self, probe: UVec2, facing: u32, level: u32) -> FVec3 {
+        let probes = self.settings.probe_count(level);
+        let index = (probe.x + probe.y * probes.x) * self.storage_facings(level) + facing;
+        self.radiance[level as usize][index as usize]
+    }
+    /// The radiance of every pre-averaged facing of the level, indexed by
+    /// `(probe.x + probe.y * probes.x) * facings + facing`.
+    pub fn level(&self, level: u32) -> &[FVec3] {
+        &self.radiance[level as usize]
+    }
+
+    /// Averages the radiance over all facings of the probe covering the pixel.
+    pub fn average(&self, pixel: UVec2, level: u32) -> FVec3 {
+        let probe = (pixel.as_vec2() / self.settings.probe_spacing(level))
+            .floor()
+            .as_uvec2()
+            .min(self.settings.probe_count(level) - 1);
+        let facings = self.storage_facings(level);
+        (0..facings)
+            .map(|facing| self.read(probe, facing, level))
+            .sum::<FVec3>()
+            / facings as f32
+    }
+
+    /// Merges every level from the top down, with the merge variant of each level.
+    pub fn update(&mut self, world: &TraceWorld, merge: &[MergeVariant]) {
+        assert_eq!(
+            merge.len(),
+            self.settings.num_cascades as usize,
+            "expected one merge variant per cascade level"
+        );
+        for level in (0..self.settings.num_cascades).rev() {
+            self.merge(world, level, merge[level as usize]);
+        }
+    }
+
+    fn merge_ray(
+        &self,
+        world: &TraceWorld,
+        probe: UVec2,
+        facing: u32,
+        level: u32,
+        variant: MergeVariant,
+    ) -> FVec3 {
+        let settings = &self.settings;
+        let probe_pos = settings.probe_location(probe, level);
+        let ray_dir = settings.facing_direction(facing, level);
+        let interval = settings.interval(level);
+
+        let next_level = level + 1;
+        let (base_index, fract) = settings.bilinear_samples(probe, next_level);
+        let max_probe = settings.probe_count(next_level).max(UVec2::ONE) - 1;
+
+        let ray = |next_probe: UVec2| {
+            let next_probe_pos = settings.probe_location(next_probe, next_level);
+            let ray_start = probe_pos + ray_dir * interval.x;
+            let ray_end = next_probe_pos + ray_dir * interval.y;
+            let ray_fluence = trace_radiance(
+                world,
+                ray_start,
+                (ray_end - ray_start).normalize(),
+                FVec2::new(0.0, (ray_end - ray_start).length()),
+            );
+            let next_radiance = if next_level < settings.num_cascades {
+                self.read(next_probe, facing, next_level)
+            } else {
+                world.environment[facing as usize]
+            };
+            ray_fluence.over_color(next_radiance)
+        };
+
+        match variant {
+            MergeVariant::Stochastic => {
+                let rand = noise::sample2(settings.noise, probe, facing, level, 0);
+                let offset = UVec2::new((rand[0] < fract.x) as u32, (rand[1] < fract.y) as u32);
+                ray(base_index + offset)
+            }
+            MergeVariant::Nearest => ray(base_index + fract.round().as_uvec2()),
+            MergeVariant::BilinearFix => (0..4)
+                .map(|i| {
+                    let (next_probe, weight) = bilinear_sample(base_index, fract, i);
+                    ray(next_probe.min(max_probe)) * weight
+                })
+                .sum(),
+            MergeVariant::Diagonal => (0..2)
+                .map(|i| {
+                    let (next_probe, weight) = diagonal_sample(base_index, fract, i);
+                    ray(next_probe.min(max_probe)) * weight
+                })
+                .sum(),
+            MergeVariant::Parallax => {
+                let target = probe_pos + ray_dir * settings.interval_end(next_level);
+                (0..4)
+                    .map(|i| {
+                        let (next_probe, weight) = bilinear_sample(base_index, fract, i);
+                        let next_probe = next_probe.min(max_probe);
+                        let next_probe_pos = settings.probe_location(next_probe, next_level);
+                        let next_dir = (target - next_probe_pos).normalize();
+                        let ray_start = probe_pos + ray_dir * interval.x;
+                        let ray_end = next_probe_pos + next_dir * interval.y;
+                        let ray_fluence = trace_radiance(
+                            world,
+                            ray_start,
+                            (ray_end - ray_start).normalize(),
+                            FVec2::new(0.0, (ray_end - ray_start).length()),
+                        );
+                        let next_radiance = self.read_direction(world, next_probe, next_dir, level);
+                        ray_fluence.over_color(next_radiance) * weight
+                    })
+                    .sum()
+            }
+        }
+    }
+    /// Reads the next level in any direction, interpolating between the two nearest facings.
+    fn read_direction(&self, world: &TraceWorld, probe: UVec2, dir: FVec2, level: u32) -> FVec3 {
+        let next_level = level + 1;
+        let facings = self.settings.facing_count(level);
+        let t = (dir.y.atan2(dir.x) / TAU + 1.0) * facings as f32 - 0.5;
+        let base = t.floor();
+        let fract = t - base;
+        let a = base as u32 % facings;
+        let b = (a + 1) % facings;
+        let read = |facing: u32| {
+            if next_level < self.settings.num_cascades {
+                self.read(probe, facing, next_level)
+            } else {
+                world.environment[facing as usize]
+            }
+        };
+        read(a) * (1.0 - fract) + read(b) * fract
+    }
+
+    fn merge(&mut self, world: &TraceWorld, level: u32, variant: MergeVariant) {
+        let probes = self.settings.probe_count(level);
+        let facings = self.storage_facings(level);
+        let branches = self.settings.branches();
+        let radiance = par_map(self.radiance[level as usize].len(), |i| {
+            let i = i as u32;
+            let facing = i % facings;
+            let probe = UVec2::new((i / facings) % probes.x, (i / facings) / probes.x);
+            (0..branches)
+                .map(|branch| {
+                    self.merge_ray(world, probe, facing * branches + branch, level, variant)
+                })
+                .sum::<FVec3>()
+                / branches as f32
+        });
+        self.radiance[level as usize] = radiance;
+    }
+}
@@ -0,0 +1,33 @@
+//! Sampling the environment, which is a list of directions in order of increasing angle.
+
+use super::*;
+
+/// Box-filters the environment over each of `facings` equal angles around the circle, interpolating
+/// linearly between its texels, so that it can have any resolution. Each cascade filters it once for
+/// the facings of its top level, so a ray leaving the cascade only reads its own facing.
+/// An empty environment is black.
+pub fn filter_env(data: &[FVec3], facings: u32) -> Vec<FVec3> {
+    let size = data.len() as u32;
+    if size == 0 {
+        return vec![FVec3::ZERO; facings as usize];
+    }
+    let samples = size.div_ceil(facings);
+    (0..facings)
+        .map(|facing| {
+            (0..samples)
+                .map(|i| {
+                    let t = (facing as f32 + (i as f32 + 0.5) / samples as f32) / facings as f32
+                        * size as f32
+                        - 0.5;
+                    let base = t.floor();
+                    let fract = t - base;
+                    // `base` is at least -1, which wraps around to the last texel.
+                    let a = ((base as i32 + 1) as u32 + size - 1) % size;
+                    let b = (a + 1) % size;
+                    data[a as usize] * (1.0 - fract) + data[b as usize] * fract
+                })
+                .sum::<FVec3>()
+                / samples as f32
+        })
+        .collect()
+}
@@ -0,0 +1,30 @@
+//! The hashes used for random numbers, matching the GPU versions bit for bit.
+
+// https://github.com/markjarzynski/PCG3D/blob/master/pcg3d.hlsl
+pub fn pcg3d(v: [u32; 3]) -> [u32; 3] {
+    let [mut x, mut y, mut z] = v.map(|v| v.wrapping_mul(1664525u32).wrapping_add(1013904223u32));
+
+    x = x.wrapping_add(y.wrapping_mul(z));
+    y = y.wrapping_add(z.wrapping_mul(x));
+    z = z.wrapping_add(x.wrapping_mul(y));
+
+    x ^= x >> 16u32;
+    y ^= y >> 16u32;
+    z ^= z >> 16u32;
+
+    x = x.wrapping_add(y.wrapping_mul(z));
+    y = y.wrapping_add(z.wrapping_mul(x));
+    z = z.wrapping_add(x.wrapping_mul(y));
+
+    [x, y, z]
+}
+
+pub fn pcg3df(v: [u32; 3]) -> [f32; 3] {
+    pcg3d(v).map(|x| x as f32 / u32::MAX as f32)
+}
+
+pub fn pcg(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405u32).wrapping_add(2891336453u32);
+    let word = ((state >> (state >> 28u32).wrapping_add(4u32)) ^ state).wrapping_mul(277803737u32);
+    (word >> 22u32) ^ word
+}
@@ -0,0 +1,208 @@
+//! A CPU implementation of the lighting pipeline of amida, mirroring the GPU kernels.
+//!
+//! This is far slower than the GPU version, but only depends on `glam` and `serde`, so it runs anywhere,
+//! and can be used to check results on machines without a graphics card.
+
+use std::f32::consts::TAU;
+
+use glam::{UVec2, Vec2 as FVec2, Vec3 as FVec3};
+use serde::{Deserialize, Serialize};
+
+pub mod cascade;
+pub mod env;
+pub mod hash;
+pub mod noise;
+mod trace;
+mod world;
+
+pub use cascade::{CascadeSettings, Cascades, IntervalSchedule, MergeVariant};
+pub use env::filter_env;
+pub use noise::NoiseSource;
+pub use trace::{trace_radiance, Fluence, TraceWorld, TRANSMITTANCE_CUTOFF};
+pub use world::{Material, World};
+
+/// Runs `f` for every index, splitting the work over all available threads.
+fn par_map<T: Send>(len: usize, f: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    let threads = std::thread::available_parallelism().map_or(1, |x| x.get());
+    let chunk_size = len.div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        let f = &f;
+        (0..len)
+            .step_by(chunk_size)
+            .map(|start| {
+                scope.spawn(move || {
+                    (start..(start + chunk_size).min(len))
+                        .map(f)
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// The parts of the settings used for lighting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub cascades: CascadeSettings,
+    pub bounce_cascades: CascadeSettings,
+    /// The merge variant of each level of `cascades`.
+    pub display_merge: Vec<MergeVariant>,
+    /// The merge variant of each level of `bounce_cascades`.
+    pub bounce_merge: Vec<MergeVariant>,
+}
+
+/// Lights the world, equivalent to the first `Renderer::step` on the GPU with the same settings.
+pub fn render(
+    world: &World,
+    environment: &[FVec3],
+    settings: &Settings,
+    num_bounces: usize,
+) -> Vec<FVec3> {
+    let size = world.size;
+    let top_facings = |cascades: &CascadeSettings| cascades.facing_count(cascades.num_cascades - 1);
+    let bounce_environment = filter_env(environment, top_facings(&settings.bounce_cascades));
+    let environment = filter_env(environment, top_facings(&settings.cascades));
+
+    let mut radiance = world.emissive.clone();
+
+    let mut bounce_cascades = Cascades::new(settings.bounce_cascades.clone());
+    for _ in 0..num_bounces {
+        bounce_cascades.update(
+            &TraceWorld {
+                size,
+                radiance: &radiance,
+                opacity: &world.opacity,
+                environment: &bounce_environment,
+            },
+            &settings.bounce_merge,
+        );
+        radiance = par_map(radiance.len(), |i| {
+            let pixel = UVec2::new(i as u32 % size[0], i as u32 / size[0]);
+            bounce_cascades.average(pixel, 0) * world.diffuse[i] + world.emissive[i]
+        });
+    }
+
+    let mut cascades = Cascades::new(settings.cascades.clone());
+    cascades.update(
+        &TraceWorld {
+            size,
+            radiance: &radiance,
+            opacity: &world.display_opacity,
+            environment: &environment,
+        },
+        &settings.display_merge,
+    );
+    par_map(radiance.len(), |i| {
+        let pixel = UVec2::new(i as u32 % size[0], i as u32 / size[0]);
+        cascades.average(pixel, 0) * world.display_diffuse[i] + world.display_emissive[i]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cascades(size: u32) -> CascadeSettings {
+        CascadeSettings {
+            base_interval: (0.0, 1.0),
+            base_probe_spacing: 1.0,
+            base_probes: UVec2::splat(size),
+            base_facings: 4,
+            num_cascades: 4,
+            spatial_factor: 1,
+            angular_factor: 2,
+            length_scaling: 4.0,
+            intervals: IntervalSchedule::Geometric,
+            noise: NoiseSource::White,
+        }
+    }
+
+    fn settings(size: u32, variant: MergeVariant) -> Settings {
+        Settings {
+            cascades: cascades(size),
+            bounce_cascades: cascades(size),
+            display_merge: vec![variant; 4],
+            bounce_merge: vec![variant; 4],
+        }
+    }
+
+    fn assert_close(a: FVec3, b: FVec3) {
+        assert!((a - b).abs().max_element() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn empty_world_shows_environment() {
+        let environment = vec![FVec3::new(0.25, 0.5, 1.0); 16];
+        for variant in MergeVariant::ALL {
+            let world = World::new([8, 8]);
+            let radiance = render(&world, &environment, &settings(8, variant), 1);
+            for pixel in radiance {
+                assert_close(pixel, environment[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn opaque_emitter_gives_its_emission() {
+        let emission = FVec3::new(2.0, 1.0, 0.5);
+        let emitter = Material {
+            emissive: emission,
+            diffuse: FVec3::ZERO,
+            opacity: FVec3::splat(1000.0),
+            display_emissive: FVec3::ZERO,
+            display_diffuse: FVec3::ONE,
+            display_opacity: FVec3::splat(1000.0),
+        };
+        let world = World::from_materials([8, 8], &[0; 64], &[emitter]);
+        let radiance = render(
+            &world,
+            &[FVec3::ONE],
+            &settings(8, MergeVariant::Nearest),
+            0,
+        );
+        for pixel in radiance {
+            assert_close(pixel, emission);
+        }
+    }
+
+    #[test]
+    fn trace_through_empty_world_is_transparent() {
+        let radiance = vec![FVec3::ZERO; 16];
+        let world = TraceWorld {
+            size: [4, 4],
+            radiance: &radiance,
+            opacity: &radiance,
+            environment: &[],
+        };
+        let fluence = trace_radiance(&world, FVec2::new(0.5, 2.0), FVec2::X, FVec2::new(0.0, 3.0));
+        assert_eq!(fluence, Fluence::transparent());
+    }
+
+    #[test]
+    fn trace_through_opaque_wall_stops() {
+        let radiance = (0..16)
+            .map(|i| if i % 4 == 2 { FVec3::ONE } else { FVec3::ZERO })
+            .collect::<Vec<_>>();
+        let opacity = radiance.iter().map(|&x| x * 1000.0).collect::<Vec<_>>();
+        let world = TraceWorld {
+            size: [4, 4],
+            radiance: &radiance,
+            opacity: &opacity,
+            environment: &[],
+        };
+        let fluence = trace_radiance(&world, FVec2::new(0.5, 2.5), FVec2::X, FVec2::new(0.0, 3.0));
+        assert_close(fluence.radiance, FVec3::ONE);
+        assert_eq!(fluence.transmittance, FVec3::ZERO);
+    }
+
+    #[test]
+    fn filter_env_averages_each_facing() {
+        let environment = (0..8).map(|i| FVec3::splat(i as f32)).collect::<Vec<_>>();
+        let filtered = filter_env(&environment, 4);
+        assert_close(filtered.iter().sum::<FVec3>() / 4.0, FVec3::splat(3.5));
+        assert_eq!(filter_env(&[], 4), vec![FVec3::ZERO; 4]);
+    }
+}
@@ -0,0 +1,221 @@
+//! The random numbers used by the stochastic merge.
+
+use std::sync::OnceLock;
+
+use hash::{pcg, pcg3d, pcg3df};
+
+use super::*;
+
+/// Where the stochastic merge gets the random numbers that pick the probe of the next level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoiseSource {
+    /// A hash of the ray, which is white noise so neighbouring probes can clump together.
+    #[default]
+    White,
+    /// A tileable blue noise texture generated at startup, which spreads the choices of neighbouring probes evenly.
+    BlueNoise,
+    /// Interleaved gradient noise, which is cheaper than blue noise but has some visible structure.
+    InterleavedGradient,
+}
+
+/// The width and height of the blue noise texture.
+pub const BLUE_NOISE_SIZE: u32 = 64;
+
+/// Additive recurrence of the R2 sequence as fractions of `2^32`, which shifts each frame
+/// by an amount that keeps every pixel evenly spread over `[0, 1)` over time.
+pub const R2: [u32; 2] = [3242174889, 2447445414];
+
+/// The blue noise texture, generated on first use and shared between all cascades.
+pub fn blue_noise() -> &'static [f32] {
+    static TEXTURE: OnceLock<Vec<f32>> = OnceLock::new();
+    TEXTURE.get_or_init(|| blue_noise_texture(BLUE_NOISE_SIZE, 0))
+}
+
+/// Generates a tileable blue noise texture with values in `[0, 1)` using the void-and-cluster method.
+pub fn blue_noise_texture(size: u32, seed: u32) -> Vec<f32> {
+    let n = (size * size) as usize;
+    let sigma = 1.5_f32;
+    // The energy a point adds at each offset from it, wrapping around the edges.
+    let kernel = (0..n as u32)
+        .map(|i| {
+            let [dx, dy] = [i % size, i / size].map(|d| d.min(size - d) as f32);
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect::<Vec<_>>();
+    let update = |energy: &mut [f32], point: usize, sign: f32| {
+        let [px, py] = [point as u32 % size, point as u32 / size];
+        for y in 0..size {
+            for x in 0..size {
+                let offset = (x + size - px) % size + (y + size - py) % size * size;
+                energy[(x + y * size) as usize] += sign * kernel[offset as usize];
+            }
+        }
+    };
+    // The set point with the most energy, or the unset one with the least.
+    let tightest_cluster = |points: &[bool], energy: &[f32]| {
+        (0..n)
+            .filter(|&i| points[i])
+            .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+    let largest_void = |points: &[bool], energy: &[f32]| {
+        (0..n)
+            .filter(|&i| !points[i])
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+
+    let mut points = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let initial = n / 10;
+    let mut state = seed;
+    let mut count = 0;
+    while count < initial {
+        state = pcg(state);
+        let point = state as usize % n;
+        if !points[point] {
+            points[point] = true;
+            update(&mut energy, point, 1.0);
+            count += 1;
+        }
+    }
+    // Spread out the initial points by moving the tightest cluster into the largest void until that is itself.
+    loop {
+        let cluster = tightest_cluster(&points, &energy);
+        points[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        let void = largest_void(&points, &energy);
+        points[void] = true;
+        update(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0; n];
+    // Rank the initial points by removing the tightest clusters, so the most clumped get the highest ranks.
+    let (mut removed_points, mut removed_energy) = (points.clone(), energy.clone());
+    for r in (0..initial).rev() {
+        let cluster = tightest_cluster(&removed_points, &removed_energy);
+        removed_points[cluster] = false;
+        update(&mut removed_energy, cluster, -1.0);
+        rank[cluster] = r;
+    }
+    // Then rank the rest by filling the largest voids. Past half full this is the same as
+    // removing the tightest clusters of the unset points, as their energies sum to a constant.
+    for r in initial..n {
+        let void = largest_void(&points, &energy);
+        points[void] = true;
+        update(&mut energy, void, 1.0);
+        rank[void] = r;
+    }
+    rank.into_iter()
+        .map(|r| (r as f32 + 0.5) / n as f32)
+        .collect()
+}
+
+/// Two random numbers in `[0, 1]` for a ray, the same as `Noise::sample2` on the GPU. Each level and facing
+/// uses a different spatial offset so that they are not correlated, and each frame a different temporal offset.
+pub fn sample2(source: NoiseSource, probe: UVec2, facing: u32, level: u32, frame: u32) -> [f32; 2] {
+    match source {
+        NoiseSource::White => {
+            let rand = pcg3df([
+                facing.wrapping_add(frame << 16),
+                probe.x,
+                probe.y.wrapping_add(level << 16),
+            ]);
+            [rand[0], rand[1]]
+        }
+        NoiseSource::BlueNoise => {
+            let offsets = pcg3d([facing, level, 0]);
+            let read = |offset: u32| {
+                let [x, y] = [
+                    probe.x.wrapping_add(offset),
+                    probe.y.wrapping_add(offset >> 16),
+                ]
+                .map(|x| x % BLUE_NOISE_SIZE);
+                blue_noise()[(x + y * BLUE_NOISE_SIZE) as usize]
+            };
+            [0, 1].map(|i| {
+                let value = read(offsets[i]) + frame.wrapping_mul(R2[i]) as f32 / 4294967296.0;
+                value - value.floor()
+            })
+        }
+        NoiseSource::InterleavedGradient => {
+            let offsets = pcg3d([facing, level, 0]).map(|x| x & 1023);
+            let time = (frame % 64) as f32 * 5.588238;
+            let gradient = |x: f32, y: f32| {
+                let x = 0.06711056 * x + 0.00583715 * y;
+                let x = 52.982_918 * (x - x.floor());
+                x - x.floor()
+            };
+            let [x, y] = [probe.x as f32 + time, probe.y as f32 + time];
+            [
+                gradient(x + offsets[0] as f32, y + offsets[1] as f32),
+                gradient(x + offsets[1] as f32, y + offsets[2] as f32),
+            ]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blue_noise_ranks_every_point_once() {
+        let size = 8;
+        let n = (size * size) as usize;
+        for seed in 0..4 {
+            let texture = blue_noise_texture(size, seed);
+            assert_eq!(texture.len(), n);
+            let mut ranks = texture
+                .iter()
+                .map(|&value| {
+                    assert!((0.0..1.0).contains(&value), "{} is not in [0, 1)", value);
+                    let rank = (value * n as f32 - 0.5).round() as usize;
+                    assert_eq!(value, (rank as f32 + 0.5) / n as f32);
+                    rank
+                })
+                .collect::<Vec<_>>();
+            ranks.sort();
+            assert_eq!(ranks, (0..n).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn sample2_is_in_range() {
+        for source in [
+            NoiseSource::White,
+            NoiseSource::BlueNoise,
+            NoiseSource::InterleavedGradient,
+        ] {
+            for frame in [0, 1, 63, 64, u32::MAX] {
+                for level in 0..3 {
+                    for facing in 0..8 {
+                        for y in 0..8 {
+                            for x in 0..8 {
+                                let probe = UVec2::new(x * 37, y * 11);
+                                for value in sample2(source, probe, facing, level, frame) {
+                                    assert!(
+                                        (0.0..=1.0).contains(&value),
+                                        "{:?} gave {} at {} facing {} level {} frame {}",
+                                        source,
+                                        value,
+                                        probe,
+                                        facing,
+                                        level,
+                                        frame
+                                    );
+                                    if source != NoiseSource::White {
+                                        assert!(value < 1.0);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Marching rays through the world.
+
+use glam::{IVec2, Vec2Swizzles};
+
+use super::*;
+
+/// Below this transmittance in every channel, rays stop and are treated as opaque.
+pub const TRANSMITTANCE_CUTOFF: f32 = 0.001;
+
+/// The light gathered along a ray, and how much of the light behind it passes through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fluence {
+    pub radiance: FVec3,
+    pub transmittance: FVec3,
+}
+impl Fluence {
+    pub fn transparent() -> Self {
+        Self {
+            radiance: FVec3::ZERO,
+            transmittance: FVec3::ONE,
+        }
+    }
+    pub fn from_color(radiance: FVec3, opacity: FVec3, segment_size: f32) -> Self {
+        let transmittance = (-opacity * segment_size).exp();
+        Self {
+            radiance: radiance * (1.0 - transmittance),
+            transmittance,
+        }
+    }
+    pub fn over(self, far: Self) -> Self {
+        Self {
+            radiance: self.radiance + self.transmittance * far.radiance,
+            transmittance: self.transmittance * far.transmittance,
+        }
+    }
+    pub fn over_color(self, far: FVec3) -> FVec3 {
+        self.radiance + self.transmittance * far
+    }
+}
+
+/// The radiance and opacity the rays are traced through.
+pub struct TraceWorld<'a> {
+    pub size: [u32; 2],
+    pub radiance: &'a [FVec3],
+    pub opacity: &'a [FVec3],
+    /// The environment over each facing of the top level, filtered with [`filter_env`](crate::filter_env).
+    pub environment: &'a [FVec3],
+}
+
+fn aabb_intersect(start: FVec2, inv_dir: FVec2, aabb_min: FVec2, aabb_max: FVec2) -> FVec2 {
+    let t0 = (aabb_min - start) * inv_dir;
+    let t1 = (aabb_max - start) * inv_dir;
+    FVec2::new(t0.min(t1).max_element(), t0.max(t1).min_element())
+}
+
+/// Marches through every pixel along the ray, equivalent to `trace_radiance` on the GPU.
+pub fn trace_radiance(
+    world: &TraceWorld,
+    ray_start: FVec2,
+    ray_dir: FVec2,
+    interval: FVec2,
+) -> Fluence {
+    let inv_dir = (ray_dir + f32::EPSILON).recip();
+
+    let aabb = aabb_intersect(
+        ray_start,
+        inv_dir,
+        FVec2::splat(0.01),
+        FVec2::new(world.size[0] as f32, world.size[1] as f32) - FVec2::splat(0.01),
+    );
+    let interval = FVec2::new(interval.x.max(aabb.x), interval.y.min(aabb.y));
+
+    if interval.x >= interval.y {
+        return Fluence::transparent();
+    }
+    let ray_start = ray_start + interval.x * ray_dir;
+
+    let mut pos = ray_start.floor().as_ivec2();
+
+    let delta_dist = inv_dir.abs();
+    let signum = FVec2::select(ray_dir.cmpeq(FVec2::ZERO), FVec2::ZERO, ray_dir.signum());
+    let ray_step = signum.as_ivec2();
+    let mut side_dist = (signum * (pos.as_vec2() - ray_start) + signum * 0.5 + 0.5) * delta_dist;
+
+    let interval_size = interval.y - interval.x;
+
+    let mut last_t = 0.0_f32;
+    let mut fluence = Fluence::transparent();
+
+    loop {
+        let next_t = side_dist.min_element();
+
+        let index = (pos.x.clamp(0, world.size[0] as i32 - 1)
+            + pos.y.clamp(0, world.size[1] as i32 - 1) * world.size[0] as i32)
+            as usize;
+        let segment_size = next_t.min(interval_size) - last_t;
+        fluence = fluence.over(Fluence::from_color(
+            world.radiance[index],
+            world.opacity[index],
+            segment_size,
+        ));
+        last_t = next_t;
+
+        if fluence
+            .transmittance
+            .cmplt(FVec3::splat(TRANSMITTANCE_CUTOFF))
+            .all()
+        {
+            fluence.transmittance = FVec3::ZERO;
+            break;
+        }
+        if next_t >= interval_size {
+            break;
+        }
+
+        let mask = side_dist.cmple(side_dist.yx());
+        side_dist += FVec2::select(mask, delta_dist, FVec2::ZERO);
+        pos += IVec2::select(mask, ray_step, IVec2::ZERO);
+    }
+    fluence
+}
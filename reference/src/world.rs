@@ -0,0 +1,59 @@
+//! The layers of a world.
+
+use super::*;
+
+/// The layers of one material, with every channel filled in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    pub emissive: FVec3,
+    pub diffuse: FVec3,
+    pub opacity: FVec3,
+    pub display_emissive: FVec3,
+    pub display_diffuse: FVec3,
+    pub display_opacity: FVec3,
+}
+
+/// The layers of a world, stored row-major.
+#[derive(Debug, Clone, PartialEq)]
+pub struct World {
+    pub size: [u32; 2],
+    pub emissive: Vec<FVec3>,
+    pub diffuse: Vec<FVec3>,
+    pub opacity: Vec<FVec3>,
+    pub display_emissive: Vec<FVec3>,
+    pub display_diffuse: Vec<FVec3>,
+    pub display_opacity: Vec<FVec3>,
+}
+impl World {
+    /// An empty world, which shows the environment unchanged.
+    pub fn new(size: [u32; 2]) -> Self {
+        let empty = vec![FVec3::ZERO; (size[0] * size[1]) as usize];
+        Self {
+            size,
+            emissive: empty.clone(),
+            diffuse: empty.clone(),
+            opacity: empty.clone(),
+            display_emissive: empty.clone(),
+            display_diffuse: vec![FVec3::ONE; (size[0] * size[1]) as usize],
+            display_opacity: empty,
+        }
+    }
+    /// A world with the material of each pixel given by its index into `materials`.
+    pub fn from_materials(size: [u32; 2], pixels: &[u32], materials: &[Material]) -> Self {
+        let layer = |f: fn(&Material) -> FVec3| {
+            pixels
+                .iter()
+                .map(|&material| f(&materials[material as usize]))
+                .collect::<Vec<_>>()
+        };
+        Self {
+            size,
+            emissive: layer(|x| x.emissive),
+            diffuse: layer(|x| x.diffuse),
+            opacity: layer(|x| x.opacity),
+            display_emissive: layer(|x| x.display_emissive),
+            display_diffuse: layer(|x| x.display_diffuse),
+            display_opacity: layer(|x| x.display_opacity),
+        }
+    }
+}
@@ -0,0 +1,437 @@
+use super::*;
+use cli::SceneArgs;
+use radiance::{MergeSettings, MergeVariant};
+use renderer::{DisplayOptions, ProbeOverlay};
+use scene::{Brush, Draw, Scene, SceneColor};
+use utils::pcg;
+
+pub fn run(args: SceneArgs) -> Result<()> {
+    let env_file_name = args.env();
+    let world_file_name = args.world();
+
+    let settings = args.load_settings()?;
+    let palette = args.load_palette()?;
+
+    let grid_size = settings.world_size;
+    let grid_dispatch = [grid_size[0], grid_size[1], 1];
+
+    let app = App::new("Amida", grid_size)
+        .scale(settings.pixel_size)
+        .agx()
+        .init();
+
+    let renderer = Renderer::new(&settings, load_materials(&settings.materials)?);
+    renderer.load_environment(&env_file_name)?;
+    let world_file_name = renderer.load_world(&world_file_name, palette)?;
+
+    let mut brush_materials = vec![];
+    let mut brushes = HashMap::new();
+    for (input, brush) in &settings.brushes {
+        let materials = brush
+            .as_slice()
+            .iter()
+            .map(|name| {
+                renderer
+                    .material_index(name)
+                    .ok_or_else(|| AmidaError::UnknownMaterial {
+                        material: name.clone(),
+                        context: format!("brush {:?}", input),
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if materials.is_empty() {
+            return Err(AmidaError::EmptyBrush {
+                context: format!("brush {:?}", input),
+            });
+        }
+        brushes.insert(
+            *input,
+            (brush_materials.len() as u32, materials.len() as u32),
+        );
+        brush_materials.extend(materials);
+    }
+    let brush_materials_buffer = DEVICE.create_buffer_from_slice(&brush_materials);
+
+    let world = renderer.world();
+    let radiance = renderer.radiance();
+    let difference = renderer.difference();
+    let materials_buffer = renderer.materials_buffer();
+    let overlay = renderer.overlay();
+
+    let display_kernel = DEVICE.create_kernel::<fn(bool, bool, Vec2<f32>, f32, bool)>(&track!(
+        |show_diff, show_overlay, cursor_pos, radius, square| {
+            let pixel = dispatch_id().xy();
+            let delta = pixel.cast_f32() - cursor_pos;
+            let dist = square.select(delta.abs().reduce_max(), delta.length());
+            app.display().write(
+                pixel,
+                radiance.read(pixel)
+                    + if show_diff {
+                        let block = BlockType::read(&difference.view(0), pixel / BlockType::SIZE);
+                        BlockType::get(block, pixel % BlockType::SIZE)
+                            .cast_u32()
+                            .cast_f32()
+                            * 5.0
+                            + (!BlockType::is_empty(block)).cast_u32().cast_f32() * 1.0
+                    } else {
+                        0.0_f32.expr()
+                    }
+                    + if show_overlay {
+                        overlay.read(pixel)
+                    } else {
+                        Vec3::splat_expr(0.0_f32)
+                    }
+                    + if dist <= radius && dist > radius - 1.0 {
+                        1.0_f32.expr()
+                    } else {
+                        0.0_f32.expr()
+                    },
+            );
+        }
+    ));
+
+    // Enter cycles from the variants in the settings through each variant on every level.
+    let merges = std::iter::once(settings.display_merge.clone())
+        .chain(MergeVariant::ALL.map(MergeSettings::uniform))
+        .collect::<Vec<_>>();
+    let mut merge_index = 0;
+    let mut num_bounces = settings.num_bounces;
+    let mut paused = settings.paused;
+    let mut run_final = settings.run_final;
+    let mut show_diff = settings.show_diff;
+    let mut show_overlay = settings.show_overlay;
+    let mut raw_radiance = settings.raw_radiance;
+    let mut display_level = settings.display_level;
+    let mut brush_radius = settings.brush_radius;
+    let mut draw_square = settings.draw_square;
+
+    let mut t = 0;
+    let mut last_options = None;
+
+    #[cfg(not(feature = "trace"))]
+    let mut total_runtime = 0.0;
+    #[cfg(feature = "trace")]
+    let mut total_runtime = vec![0.0; num_bounces + 1];
+
+    #[rustfmt::skip]
+    let draw_kernel = DEVICE.create_kernel::<fn(Vec2<f32>, f32, bool, u32, u32)>(&track!(
+        |pos, radius, square, material_start, material_len| {
+            let material = materials_buffer.read(brush_materials_buffer.read(material_start + pcg((dispatch_id().x << 16) + dispatch_id().y) % material_len));
+
+            let delta = dispatch_id().xy().cast_f32() - pos;
+            if square.select(delta.abs().reduce_max(), delta.length()) <= radius {
+                world.write_pixel(dispatch_id().xy(), material);
+            }
+        }
+    ));
+
+    let rect_brush = DEVICE.create_kernel::<fn(Vec2<f32>, Vec2<f32>, SceneColor)>(&track!(
+        |center, size, color| {
+            let pos = dispatch_id().xy();
+            if ((pos.cast_f32() + 0.5 - center).abs() < size).all() {
+                world.emissive.write(pos, color.emission);
+                world.display_opacity.write(pos, color.opacity);
+            }
+        }
+    ));
+    let circle_brush =
+        DEVICE.create_kernel::<fn(Vec2<f32>, f32, SceneColor)>(&track!(|center, radius, color| {
+            let pos = dispatch_id().xy();
+            if (pos.cast_f32() + 0.5 - center).length() < radius {
+                world.emissive.write(pos, color.emission);
+                world.display_opacity.write(pos, color.opacity);
+            }
+        }));
+
+    let julia = DEVICE.create_kernel::<fn()>(&track!(|| {
+        let c = Vec2::<f32>::new(-0.835, -0.2321);
+        let r = 2.0;
+        assert!(r * r - r >= (c.x * c.x + c.y * c.y).sqrt());
+
+        let pos = dispatch_id().xy().cast_f32() + 0.5;
+        let pos = 2.0 * ((pos / dispatch_size().xy().cast_f32()) - Vec2::expr(0.5, 0.5)) * r * 0.7;
+        let theta = 0.0_f32;
+        let pos = Vec2::expr(
+            pos.x * theta.cos() + pos.y * theta.sin(),
+            -pos.x * theta.sin() + pos.y * theta.cos(),
+        );
+        let z = pos.var();
+
+        let iter = u32::MAX.var();
+        for i in 0_u32..1000 {
+            *z = Vec2::expr(z.x * z.x - z.y * z.y, 2.0 * z.x * z.y) + c;
+            if z.length() > r {
+                *iter = i;
+                break;
+            }
+        }
+        let color = if iter > 30 {
+            let iter = keter::min(iter, 1000);
+            let j = iter.cast_f32() / 60.0;
+            (
+                Vec3::<f32>::expr(0.1 * j, 0.0, 0.0),
+                Vec3::<f32>::splat_expr(0.3),
+            )
+        } else {
+            let j = iter.cast_f32() / 30.0;
+            let l = if iter % 2 == 0 {
+                1.0_f32.expr()
+            } else {
+                0.0.expr()
+            };
+            (
+                Vec3::<f32>::expr(0.0, 0.0, 0.0),
+                (Vec3::<f32>::expr(0.25, 1.0, 2.5) * l * j * j),
+            )
+        };
+        world.emissive.write(dispatch_id().xy(), color.0);
+        world.display_opacity.write(dispatch_id().xy(), color.1);
+    }));
+
+    julia.dispatch_blocking([world.width(), world.height(), 1]);
+
+    rect_brush.dispatch(
+        [world.width(), world.height(), 1],
+        &Vec2::new(0.0, 0.0),
+        &Vec2::new(10000.0, 10.0),
+        &SceneColor::new(Vec3::splat(5.0), Vec3::splat(0.5)),
+    );
+
+    // let scene = Scene::point();
+    // for Draw {
+    //     brush,
+    //     center,
+    //     color,
+    // } in scene.draws
+    // {
+    //     match brush {
+    //         Brush::Rect(width, height) => {
+    //             rect_brush.dispatch(
+    //                 [world.width(), world.height(), 1],
+    //                 &center,
+    //                 &Vec2::new(width, height),
+    //                 &color,
+    //             );
+    //         }
+    //         Brush::Circle(radius) => {
+    //             circle_brush.dispatch([world.width(), world.height(), 1], &center, &radius, &color);
+    //         }
+    //     }
+    // }
+
+    #[rustfmt::skip]
+    let draw = |pos: Vec2<f32>, r: f32, sq: bool, brush: (u32, u32)| {
+        draw_kernel.dispatch(
+            grid_dispatch,
+            &pos,
+            &r,
+            &sq,
+            &brush.0,
+            &brush.1,
+        );
+        renderer.reset_accumulation();
+    };
+
+    app.run(|rt| {
+        display_kernel
+            .dispatch_async(
+                grid_dispatch,
+                &show_diff,
+                &(show_overlay && run_final),
+                &rt.cursor_position,
+                &brush_radius,
+                &draw_square,
+            )
+            .debug("Display")
+            .execute_blocking();
+
+        #[cfg(feature = "record")]
+        if rt.pressed_key(KeyCode::KeyX) {
+            println!("Recording");
+            rt.begin_recording(None, false);
+        }
+
+        if rt.mouse_scroll != Vec2::splat(0.0) {
+            brush_radius = (brush_radius + rt.mouse_scroll.y).max(1.0);
+            println!("Brush radius: {}", brush_radius);
+        }
+        if rt.just_pressed_key(KeyCode::Enter) {
+            merge_index = (merge_index + 1) % merges.len();
+            if merge_index == 0 {
+                println!("Merge variants: from settings");
+            } else {
+                println!("Merge variant: {}", merges[merge_index].variant);
+            }
+        } else if rt.just_pressed_key(KeyCode::KeyQ) {
+            draw_square = !draw_square;
+            println!("Draw square: {}", draw_square);
+        } else if rt.just_pressed_key(KeyCode::KeyE) {
+            display_level = (display_level + 1) % renderer.num_cascades();
+            println!("Display level: {}", display_level);
+        } else if rt.just_pressed_key(KeyCode::KeyB) {
+            num_bounces = (num_bounces + 1) % 4;
+            #[cfg(feature = "trace")]
+            {
+                total_runtime = vec![0.0; num_bounces + 1];
+            }
+            println!("Bounces: {}", num_bounces);
+        } else if rt.just_pressed_key(KeyCode::KeyF) {
+            run_final = !run_final;
+            println!("Display final bounce: {}", run_final);
+        } else if rt.just_pressed_key(KeyCode::KeyD) {
+            show_diff = !show_diff;
+            println!("Show difference map: {}", show_diff);
+        } else if rt.just_pressed_key(KeyCode::KeyO) {
+            show_overlay = !show_overlay;
+            println!("Show probe overlay: {}", show_overlay);
+        } else if rt.just_pressed_key(KeyCode::KeyT) {
+            renderer.set_accumulation(match renderer.accumulation() {
+                Some(_) => None,
+                // Averages over roughly the last 20 frames.
+                None => Some(settings.accumulation.unwrap_or(0.05)),
+            });
+            println!("Temporal accumulation: {:?}", renderer.accumulation());
+        } else if rt.just_pressed_key(KeyCode::KeyR) {
+            raw_radiance = !raw_radiance;
+            println!("Display raw radiance: {}", raw_radiance);
+        } else if rt.just_pressed_key(KeyCode::KeyS) {
+            let mut path = PathBuf::from(&world_file_name);
+            if !rt.pressed_key(KeyCode::ControlLeft) {
+                let ext = path.extension().unwrap_or_default();
+                let mut file_name = path.file_stem().unwrap().to_owned();
+                file_name.push("-");
+                file_name.push(
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .expect("Temporal anomaly detected.")
+                        .as_millis()
+                        .to_string(),
+                );
+                file_name.push(".");
+                file_name.push(ext);
+                path.set_file_name(file_name);
+            }
+            match world.save(&path) {
+                Ok(()) => println!("Saved to {}", path.display()),
+                Err(err) => eprintln!("Could not save: {}", err),
+            }
+        } else if rt.just_pressed_key(KeyCode::KeyC) {
+            let path = Path::new(&world_file_name);
+            let mut dir_name = path.file_stem().unwrap().to_owned();
+            dir_name.push("-cascades");
+            let dir = path.with_file_name(dir_name);
+            match renderer.dump_cascades(&dir) {
+                Ok(()) => println!("Dumped the cascades to {}", dir.display()),
+                Err(err) => eprintln!("Could not dump the cascades: {}", err),
+            }
+        } else if rt.just_pressed_key(KeyCode::KeyL) {
+            match world.load(&world_file_name) {
+                Ok(()) => {
+                    renderer.reset_accumulation();
+                    println!("Loaded");
+                }
+                Err(err) => eprintln!("Could not load: {}", err),
+            }
+        } else if rt.just_pressed_key(KeyCode::Space) {
+            paused = !paused;
+            if paused {
+                println!("Paused");
+            } else {
+                println!("Running");
+            }
+        } else {
+            for (&input, &brush) in &brushes {
+                match input {
+                    BrushInput::Key(key) => {
+                        if rt.just_pressed_key(key) {
+                            let pos = rt.cursor_position;
+                            draw(pos, brush_radius, draw_square, brush);
+                        }
+                    }
+                    BrushInput::Mouse(button) => {
+                        if rt.pressed_button(button) {
+                            let pos = rt.cursor_position;
+                            draw(pos, brush_radius, draw_square, brush);
+                        }
+                    }
+                }
+            }
+        }
+
+        if paused {
+            return;
+        }
+
+        t += 1;
+
+        let options = (
+            merge_index,
+            num_bounces,
+            run_final,
+            display_level,
+            raw_radiance,
+        );
+        if last_options != Some(options) {
+            renderer.reset_accumulation();
+            last_options = Some(options);
+        }
+
+        let commands = renderer.commands(
+            num_bounces,
+            &merges[merge_index],
+            DisplayOptions {
+                run_final,
+                display_level,
+                raw_radiance,
+                overlay: show_overlay.then_some(ProbeOverlay {
+                    level: display_level,
+                    cursor: rt.cursor_position,
+                }),
+            },
+        );
+        #[cfg(not(feature = "trace"))]
+        {
+            let start = std::time::Instant::now();
+            commands.execute();
+            total_runtime += start.elapsed().as_secs_f32() * 1000.0;
+            if t % 1000 == 0 {
+                println!("Frame time: {:?}ms", total_runtime / 1000.0);
+                total_runtime = 0.0;
+            }
+        }
+        #[cfg(feature = "trace")]
+        {
+            let timings = commands.execute_timed();
+            if rt.just_pressed_key(KeyCode::Backslash) {
+                println!("{:?}", timings);
+            }
+            {
+                let mut index = 0;
+                let mut last_merge = false;
+                for (name, value) in timings.iter() {
+                    if name.contains("merge level") {
+                        total_runtime[index] += *value;
+                        last_merge = true;
+                    } else {
+                        if last_merge {
+                            index += 1;
+                        }
+                        last_merge = false;
+                    }
+                }
+            }
+            if t % 20 == 0 {
+                println!("Runtime:");
+                if num_bounces > 0 {
+                    for (i, time) in total_runtime.iter().enumerate().take(num_bounces) {
+                        println!("  Bounce {}: {}ms", i, time / 20.0);
+                    }
+                }
+                println!("  Display: {}ms", total_runtime[num_bounces] / 20.0 / 4.0);
+                println!("  Total: {}ms", total_runtime.iter().sum::<f32>() / 20.0);
+                total_runtime.fill(0.0);
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use amida::cascade::BranchReduction;
+use amida::renderer::DisplayOptions;
+use amida::{load_materials, load_palette, load_settings, Renderer};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::Parser;
+
+/// Benchmark every named command of the pipeline over a set of settings and worlds.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Settings files to benchmark.
+    #[arg(short, long, num_args = 1.., default_value = "settings/default.ron")]
+    settings: Vec<String>,
+    /// Worlds to benchmark: layered TIFFs, or images which are loaded with `--palette`.
+    #[arg(short, long, num_args = 1.., default_value = "world/room.png")]
+    worlds: Vec<String>,
+    /// Palette used to load image worlds.
+    #[arg(short, long, default_value = "room-palette.ron")]
+    palette: String,
+    /// The environment map.
+    #[arg(short, long, default_value = "env/default.tiff")]
+    env: String,
+    /// Branch reductions to benchmark each settings file with, overriding both cascades.
+    /// Uses the reductions in the settings if not given.
+    #[arg(short, long, num_args = 1.., value_parser = reduction())]
+    reductions: Vec<BranchReduction>,
+    /// Frames run before measuring.
+    #[arg(long, default_value_t = 10)]
+    warmup: u32,
+    /// Frames measured.
+    #[arg(short, long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+    frames: u32,
+    /// Output file; written as JSON if the extension is `.json`, and CSV otherwise.
+    #[arg(short, long, default_value = "bench.csv")]
+    output: PathBuf,
+}
+
+fn reduction() -> impl TypedValueParser<Value = BranchReduction> {
+    PossibleValuesParser::new(["shared", "warp"]).map(|reduction| match reduction.as_str() {
+        "warp" => BranchReduction::Warp,
+        _ => BranchReduction::Shared,
+    })
+}
+
+struct Record {
+    settings: String,
+    world: String,
+    command: String,
+    samples: Vec<f32>,
+}
+impl Record {
+    fn mean(&self) -> f32 {
+        self.samples.iter().sum::<f32>() / self.samples.len() as f32
+    }
+    fn percentile(&self, p: f32) -> f32 {
+        let mut sorted = self.samples.clone();
+        sorted.sort_by(f32::total_cmp);
+        let index = ((sorted.len() - 1) as f32 * p).round() as usize;
+        sorted[index]
+    }
+}
+
+/// Runs the frames, summing the time of each named command within a frame.
+/// Commands are returned in order of their first appearance, followed by the total.
+fn bench(
+    renderer: &Renderer,
+    settings: &amida::data::Settings,
+    args: &Args,
+) -> Vec<(String, Vec<f32>)> {
+    let display = DisplayOptions {
+        run_final: settings.run_final,
+        display_level: settings.display_level,
+        raw_radiance: settings.raw_radiance,
+        overlay: None,
+    };
+    for _ in 0..args.warmup {
+        renderer
+            .commands(settings.num_bounces, &settings.display_merge, display)
+            .execute();
+    }
+
+    let mut order = Vec::new();
+    let mut samples = HashMap::<String, Vec<f32>>::new();
+    let mut totals = Vec::new();
+    for frame in 0..args.frames as usize {
+        let timings = renderer
+            .commands(settings.num_bounces, &settings.display_merge, display)
+            .execute_timed();
+        let mut total = 0.0;
+        for (name, value) in timings.iter() {
+            let entry = samples.entry(name.to_string()).or_insert_with(|| {
+                order.push(name.to_string());
+                Vec::new()
+            });
+            // Commands that didn't run in earlier frames count as zero.
+            entry.resize(frame + 1, 0.0);
+            entry[frame] += *value;
+            total += *value;
+        }
+        totals.push(total);
+    }
+    let mut result = order
+        .into_iter()
+        .map(|name| {
+            let mut values = samples.remove(&name).unwrap();
+            values.resize(args.frames as usize, 0.0);
+            (name, values)
+        })
+        .collect::<Vec<_>>();
+    result.push(("Total".to_string(), totals));
+    result
+}
+
+/// Quotes a CSV field if it contains a separator, quote or newline, doubling any quotes.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn write_csv(records: &[Record]) -> String {
+    let mut out = "settings,world,command,samples,mean_ms,median_ms,p95_ms\n".to_string();
+    for record in records {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{}",
+            csv_field(&record.settings),
+            csv_field(&record.world),
+            csv_field(&record.command),
+            record.samples.len(),
+            record.mean(),
+            record.percentile(0.5),
+            record.percentile(0.95)
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn write_json(records: &[Record]) -> String {
+    let entries = records
+        .iter()
+        .map(|record| {
+            serde_json::json!({
+                "settings": record.settings,
+                "world": record.world,
+                "command": record.command,
+                "samples": record.samples.len(),
+                "mean_ms": record.mean(),
+                "median_ms": record.percentile(0.5),
+                "p95_ms": record.percentile(0.95),
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_string_pretty(&entries).unwrap() + "\n"
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let mut records = Vec::new();
+    for path in &args.settings {
+        let settings = match load_settings(path) {
+            Ok(settings) => settings,
+            Err(err) => {
+                eprintln!("Skipping {}: {}", path, err);
+                continue;
+            }
+        };
+        let variants = if args.reductions.is_empty() {
+            vec![(path.clone(), settings)]
+        } else {
+            args.reductions
+                .iter()
+                .map(|&reduction| {
+                    let mut settings = settings.clone();
+                    settings.cascades.reduction = reduction;
+                    settings.bounce_cascades.reduction = reduction;
+                    (format!("{} ({:?})", path, reduction), settings)
+                })
+                .collect()
+        };
+        for (settings_path, settings) in &variants {
+            let materials = match load_materials(&settings.materials) {
+                Ok(materials) => materials,
+                Err(err) => {
+                    eprintln!("Skipping {}: {}", settings_path, err);
+                    continue;
+                }
+            };
+            let renderer = Renderer::new(settings, materials);
+            if let Err(err) = renderer.load_environment(&args.env) {
+                eprintln!("Skipping {}: {}", settings_path, err);
+                continue;
+            }
+            for world in &args.worlds {
+                let is_image = Path::new(world)
+                    .extension()
+                    .is_some_and(|ext| ext != "tiff");
+                let loaded = (|| -> amida::error::Result<_> {
+                    let palette = is_image.then(|| load_palette(&args.palette)).transpose()?;
+                    renderer.load_world(world, palette)
+                })();
+                if let Err(err) = loaded {
+                    eprintln!("Skipping {} with {}: {}", world, settings_path, err);
+                    continue;
+                }
+
+                println!("{} with {}:", world, settings_path);
+                for (command, samples) in bench(&renderer, settings, &args) {
+                    let record = Record {
+                        settings: settings_path.clone(),
+                        world: world.clone(),
+                        command,
+                        samples,
+                    };
+                    println!(
+                        "  {:<24} mean {:>8.3}ms  median {:>8.3}ms  p95 {:>8.3}ms",
+                        record.command,
+                        record.mean(),
+                        record.percentile(0.5),
+                        record.percentile(0.95)
+                    );
+                    records.push(record);
+                }
+            }
+        }
+    }
+
+    let output = if args.output.extension().is_some_and(|ext| ext == "json") {
+        write_json(&records)
+    } else {
+        write_csv(&records)
+    };
+    if let Err(err) = std::fs::write(&args.output, output) {
+        eprintln!("Error: {}: {}", args.output.display(), err);
+        std::process::exit(1);
+    }
+    println!(
+        "Wrote {} records to {}",
+        records.len(),
+        args.output.display()
+    );
+}
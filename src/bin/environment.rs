@@ -35,5 +35,5 @@ fn main() {
             skylight(angle)
         })
         .collect::<Vec<_>>();
-    save_env(&data, "env/sunset.tiff");
+    save_env(&data, "env/sunset.tiff").unwrap();
 }
@@ -1,18 +1,178 @@
+use color::Packable;
+use error::Diagnostic;
+use glam::{UVec2, Vec2 as FVec2};
+use noise::NoiseSource;
 use trace::Interval;
 
+pub use amida_reference::IntervalSchedule;
+
 use super::*;
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CascadeSettings {
     pub base_interval: (f32, f32),
     pub base_probe_spacing: f32,
     pub base_size: CascadeSize,
     pub num_cascades: u32,
-    pub spatial_factor: u32, // default 1
-    pub angular_factor: u32, // default 2
-    pub length_scaling: f32, // default 4.0
+    #[serde(default = "default_spatial_factor")]
+    pub spatial_factor: u32,
+    #[serde(default = "default_angular_factor")]
+    pub angular_factor: u32,
+    #[serde(default = "default_length_scaling")]
+    pub length_scaling: f32,
+    #[serde(default)]
+    pub intervals: IntervalSchedule,
+    #[serde(default)]
+    pub layout: StorageLayout,
+    #[serde(default)]
+    pub format: StorageFormat,
+    /// The random numbers used by the stochastic merge.
+    #[serde(default)]
+    pub noise: NoiseSource,
+    #[serde(default)]
+    pub reduction: BranchReduction,
+}
+fn default_spatial_factor() -> u32 {
+    1
+}
+fn default_angular_factor() -> u32 {
+    2
+}
+fn default_length_scaling() -> f32 {
+    4.0
+}
+
+/// How the rays of a level are ordered in [`CascadeStorage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageLayout {
+    /// All facings of a probe are next to each other.
+    #[default]
+    ProbeMajor,
+    /// All probes of a facing are next to each other.
+    DirectionMajor,
+    /// Probe-major within square tiles of probes with the given width, which are stored one after another.
+    /// Levels are padded to a whole number of tiles.
+    Tiled(u32),
+}
+
+/// How the radiance of each ray is encoded in [`CascadeStorage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageFormat {
+    /// Three 32 bit floats, padded to 16 bytes.
+    #[default]
+    Full,
+    /// Three half floats in 8 bytes, with a relative error of at most 2^-11.
+    /// Values above 65504 are clamped.
+    Half,
+    /// A 9 bit mantissa per channel with a shared exponent in 4 bytes.
+    /// The brightest channel has a relative error of at most 2^-10, but dimmer channels lose precision.
+    Rgb9e5,
+}
+impl StorageFormat {
+    /// The size of the radiance of one ray in bytes.
+    pub fn ray_bytes(self) -> usize {
+        match self {
+            Self::Full => std::mem::size_of::<Radiance>(),
+            Self::Half => std::mem::size_of::<Vec2<u32>>(),
+            Self::Rgb9e5 => std::mem::size_of::<u32>(),
+        }
+    }
+}
+
+/// How the merge kernels sum the rays of each stored facing before averaging them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BranchReduction {
+    /// Writes every ray to shared memory, and reads them back after a barrier.
+    #[default]
+    Shared,
+    /// Shuffles between the lanes of a warp, which needs no shared memory or barriers.
+    /// Falls back to `Shared` when the rays of a facing don't fit in a warp of the device.
+    /// Not yet measured to be faster, so compare both with the `bench` binary before using it.
+    Warp,
 }
+
+/// How many rays to spend, when deriving settings from the world size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    Fast,
+    #[default]
+    Balanced,
+    High,
+}
+
 impl CascadeSettings {
+    /// Cascades for the final pass, which need probes at every pixel (except when fast) for sharp shadows.
+    pub fn display(world_size: [u32; 2], quality: Quality) -> Self {
+        let (base_probe_spacing, facings) = match quality {
+            Quality::Fast => (2.0, 4),
+            Quality::Balanced => (1.0, 4),
+            Quality::High => (1.0, 8),
+        };
+        // Finer angles can look further before rays from neighboring probes diverge.
+        let end = base_probe_spacing * facings as f32 / 4.0;
+        Self::derive(world_size, base_probe_spacing, facings, (0.0, end))
+    }
+    /// Cascades for the bounces, which are blurred by the diffuse lighting so can use sparser probes.
+    pub fn bounce(world_size: [u32; 2], quality: Quality) -> Self {
+        let base_probe_spacing = match quality {
+            Quality::Fast => 4.0,
+            Quality::Balanced => 2.0,
+            Quality::High => 1.0,
+        };
+        Self::derive(
+            world_size,
+            base_probe_spacing,
+            16,
+            (0.75 * base_probe_spacing, 3.0 * base_probe_spacing),
+        )
+    }
+    /// Grows the intervals as fast as the angular resolution, which keeps the penumbra condition,
+    /// and adds levels until the last one reaches across the world.
+    fn derive(
+        world_size: [u32; 2],
+        base_probe_spacing: f32,
+        facings: u32,
+        base_interval: (f32, f32),
+    ) -> Self {
+        let spatial_factor = 1;
+        let angular_factor = 2;
+        let length_scaling = (1 << angular_factor) as f32;
+        let diagonal = (world_size[0] as f32).hypot(world_size[1] as f32);
+        let num_cascades = 1
+            + (diagonal / base_interval.1)
+                .log(length_scaling)
+                .ceil()
+                .max(0.0) as u32;
+        Self {
+            base_interval,
+            base_probe_spacing,
+            base_size: CascadeSize {
+                probes: Vec2::new(
+                    (world_size[0] as f32 / base_probe_spacing).ceil() as u32,
+                    (world_size[1] as f32 / base_probe_spacing).ceil() as u32,
+                ),
+                facings,
+            },
+            num_cascades,
+            spatial_factor,
+            angular_factor,
+            length_scaling,
+            intervals: IntervalSchedule::Geometric,
+            layout: StorageLayout::ProbeMajor,
+            format: StorageFormat::Full,
+            noise: NoiseSource::White,
+            reduction: BranchReduction::Shared,
+        }
+    }
+    /// Whether each interval grows no faster than the angular resolution,
+    /// and the probe spacing no faster than the interval.
+    pub fn satisfies_penumbra(&self) -> bool {
+        (1..self.num_cascades).all(|level| {
+            let growth = self.interval_end_host(level) / self.interval_end_host(level - 1);
+            growth <= self.branches() as f32 && self.spacing() as f32 <= growth
+        })
+    }
     pub fn spacing(&self) -> u32 {
         1 << self.spatial_factor
     }
@@ -34,10 +194,35 @@ impl CascadeSettings {
     pub fn probe_spacing(&self, level: Expr<u32>) -> Expr<f32> {
         self.base_probe_spacing * (1 << (self.spatial_factor * level)).cast_f32()
     }
-    #[tracked]
     pub fn interval_end(&self, level: Expr<u32>) -> Expr<f32> {
+        match self.intervals {
+            IntervalSchedule::Geometric => self.geometric_interval_end(level),
+            _ => self.scheduled_interval_end(level),
+        }
+    }
+    #[tracked]
+    fn geometric_interval_end(&self, level: Expr<u32>) -> Expr<f32> {
         self.base_interval.1 * self.length_scaling.expr().powi(level.cast_i32())
     }
+    /// Selects from the ends computed on the host, as they don't follow a formula.
+    /// Past the last cascade and the end of the list, the ends grow by `length_scaling`,
+    /// the same as [`IntervalSchedule::end`], so the parallax merge of the top level can target the level above it.
+    #[tracked]
+    fn scheduled_interval_end(&self, level: Expr<u32>) -> Expr<f32> {
+        let last = self.num_cascades.max(self.listed_interval_ends());
+        let end = (self.interval_end_host(last)
+            * self
+                .length_scaling
+                .expr()
+                .powi(level.cast_i32() - last as i32))
+        .var();
+        for l in 0..last {
+            if level == l {
+                *end = self.interval_end_host(l);
+            }
+        }
+        **end
+    }
     #[tracked]
     pub fn interval(&self, level: Expr<u32>) -> Expr<Interval> {
         Vec2::expr(
@@ -49,23 +234,40 @@ impl CascadeSettings {
             self.interval_end(level),
         )
     }
+    /// Rounded up, so that the last probes of a level still cover the edge of the world.
     #[tracked]
     pub fn probe_count(&self, level: Expr<u32>) -> Expr<Vec2<u32>> {
-        self.base_size.probes >> (level * self.spatial_factor)
+        let shift = level * self.spatial_factor;
+        (self.base_size.probes.expr() + Vec2::splat_expr((1_u32 << shift) - 1)) >> shift
+    }
+    /// The probe covering the pixel, clamped to the last probe for a world that isn't a multiple of the spacing.
+    #[tracked]
+    pub fn pixel_probe(&self, pixel: Expr<Vec2<u32>>, level: Expr<u32>) -> Expr<Vec2<u32>> {
+        keter::min(
+            (pixel.cast_f32() / self.probe_spacing(level))
+                .floor()
+                .cast_u32(),
+            self.probe_count(level) - 1,
+        )
     }
     #[tracked]
     pub fn facing_count(&self, level: Expr<u32>) -> Expr<u32> {
         self.base_size.facings << (level * self.angular_factor)
     }
     pub fn level_size(&self, level: u32) -> CascadeSize {
+        let spacing = 1 << (level * self.spatial_factor);
         CascadeSize {
             probes: Vec2::new(
-                self.base_size.probes.x >> (level * self.spatial_factor), // .max(1)
-                self.base_size.probes.y >> (level * self.spatial_factor), // TODO: That'd break cascade_total_size.
+                self.base_size.probes.x.div_ceil(spacing),
+                self.base_size.probes.y.div_ceil(spacing),
             ),
             facings: self.base_size.facings << (level * self.angular_factor),
         }
     }
+    /// The facings of the top level, over which the environment is filtered for the rays leaving the cascades.
+    pub fn environment_facings(&self) -> u32 {
+        self.level_size(self.num_cascades - 1).facings
+    }
     #[tracked]
     pub fn level_size_expr(&self, level: Expr<u32>) -> Expr<CascadeSize> {
         CascadeSize::from_comps_expr(CascadeSizeComps {
@@ -73,9 +275,35 @@ impl CascadeSettings {
             facings: self.facing_count(level),
         })
     }
+    /// The settings of the storage, which only has one facing per group of branches as they are averaged on merge.
+    pub fn storage_settings(&self) -> CascadeSettings {
+        CascadeSettings {
+            base_size: CascadeSize {
+                facings: self.base_size.facings / self.branches(),
+                ..self.base_size
+            },
+            ..self.clone()
+        }
+    }
+    /// The number of rays stored for a level, including any padding of the layout.
+    pub fn level_total_size(&self, level: u32) -> u32 {
+        let size = self.level_size(level);
+        let probes = match self.layout {
+            StorageLayout::Tiled(tile) => [
+                size.probes.x.next_multiple_of(tile),
+                size.probes.y.next_multiple_of(tile),
+            ],
+            _ => [size.probes.x, size.probes.y],
+        };
+        probes[0] * probes[1] * size.facings
+    }
+    /// The number of rays in all levels below `level`, which is where the level starts in storage.
+    pub fn level_offset(&self, level: u32) -> u32 {
+        (0..level).map(|l| self.level_total_size(l)).sum()
+    }
+    /// The number of rays in all levels.
     pub fn cascade_total_size(&self) -> u32 {
-        assert!(self.spatial_factor * 2 >= self.angular_factor); // So they cancel out. Still not quite exact.
-        self.base_size.probes.x * self.base_size.probes.y * self.base_size.facings
+        self.level_offset(self.num_cascades)
     }
     #[tracked]
     pub fn bilinear_samples(
@@ -84,16 +312,145 @@ impl CascadeSettings {
         next_level: Expr<u32>,
     ) -> Expr<BilinearSamples> {
         let next_level_probe_location = ((probe.cast_f32() + 0.5) / self.spacing() as f32) - 0.5;
-        let next_level_probe_location = next_level_probe_location.clamp(
-            0.0,
-            (self.base_size.probes >> (next_level * self.spatial_factor)).cast_f32() - 1.0,
+        let probe_count = self.probe_count(next_level);
+        let next_level_probe_location =
+            next_level_probe_location.clamp(0.0, probe_count.cast_f32() - 1.0);
+        // Keep the second sample inside the level at the far edges, using a weight of 1 instead.
+        let base_index = keter::min(
+            next_level_probe_location.floor().cast_u32(),
+            keter::max(probe_count, Vec2::splat_expr(2_u32)) - 2,
         );
-        let base_index = next_level_probe_location.floor().cast_u32();
-        let fract = next_level_probe_location.fract();
+        let fract = next_level_probe_location - base_index.cast_f32();
         BilinearSamples::from_comps_expr(BilinearSamplesComps { base_index, fract })
     }
 }
 
+// Host versions of the above.
+impl CascadeSettings {
+    /// The number of levels whose end is given by the interval schedule, rather than growing by `length_scaling`.
+    fn listed_interval_ends(&self) -> u32 {
+        match &self.intervals {
+            IntervalSchedule::Geometric => 0,
+            IntervalSchedule::Ends(ends) => ends.len() as u32,
+            IntervalSchedule::Scaling(scaling) => scaling.len() as u32 + 1,
+        }
+    }
+    pub fn interval_end_host(&self, level: u32) -> f32 {
+        self.intervals
+            .end(self.base_interval.1, self.length_scaling, level)
+    }
+    pub fn interval_host(&self, level: u32) -> FVec2 {
+        FVec2::new(
+            if level == 0 {
+                self.base_interval.0
+            } else {
+                self.interval_end_host(level - 1)
+            },
+            self.interval_end_host(level),
+        )
+    }
+    /// The parts of the settings used by the CPU reference.
+    pub fn reference(&self) -> amida_reference::CascadeSettings {
+        amida_reference::CascadeSettings {
+            base_interval: self.base_interval,
+            base_probe_spacing: self.base_probe_spacing,
+            base_probes: UVec2::new(self.base_size.probes.x, self.base_size.probes.y),
+            base_facings: self.base_size.facings,
+            num_cascades: self.num_cascades,
+            spatial_factor: self.spatial_factor,
+            angular_factor: self.angular_factor,
+            length_scaling: self.length_scaling,
+            intervals: self.intervals.clone(),
+            noise: self.noise,
+        }
+    }
+    /// Checks the settings against the size of the world, returning any problems found.
+    pub fn validate(&self, world_size: [u32; 2]) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        if self.num_cascades == 0 {
+            diagnostics.push(Diagnostic::error("there must be at least one cascade"));
+            return diagnostics;
+        }
+        if self.base_size.facings == 0 {
+            diagnostics.push(Diagnostic::error("there must be at least one facing"));
+        } else if self.base_size.facings % self.branches() != 0 {
+            diagnostics.push(Diagnostic::error(format!(
+                "{} facings are not divisible by the {} branches, which is required for pre-averaging",
+                self.base_size.facings,
+                self.branches()
+            )));
+        }
+
+        let covered = [
+            self.base_size.probes.x as f32 * self.base_probe_spacing,
+            self.base_size.probes.y as f32 * self.base_probe_spacing,
+        ];
+        if covered[0] < world_size[0] as f32 || covered[1] < world_size[1] as f32 {
+            diagnostics.push(Diagnostic::error(format!(
+                "{}x{} probes with a spacing of {} only cover {}x{} of the {}x{} world",
+                self.base_size.probes.x,
+                self.base_size.probes.y,
+                self.base_probe_spacing,
+                covered[0],
+                covered[1],
+                world_size[0],
+                world_size[1]
+            )));
+        } else if covered[0] >= world_size[0] as f32 + self.base_probe_spacing
+            || covered[1] >= world_size[1] as f32 + self.base_probe_spacing
+        {
+            diagnostics.push(Diagnostic::warning(format!(
+                "{}x{} probes with a spacing of {} extend past the {}x{} world",
+                self.base_size.probes.x,
+                self.base_size.probes.y,
+                self.base_probe_spacing,
+                world_size[0],
+                world_size[1]
+            )));
+        }
+
+        if self.layout == StorageLayout::Tiled(0) {
+            diagnostics.push(Diagnostic::error(
+                "storage tiles must be at least 1 probe wide",
+            ));
+        }
+        if let IntervalSchedule::Ends(ends) = &self.intervals {
+            if ends.len() != self.num_cascades as usize {
+                diagnostics.push(Diagnostic::error(format!(
+                    "expected {} interval ends, one for each level, but found {}",
+                    self.num_cascades,
+                    ends.len()
+                )));
+            }
+        }
+        for level in 0..self.num_cascades {
+            let interval = self.interval_host(level);
+            if interval.y <= interval.x {
+                diagnostics.push(Diagnostic::error(format!(
+                    "interval of level {} ends at {}, which is not after its start at {}",
+                    level, interval.y, interval.x
+                )));
+            }
+        }
+        if !self.satisfies_penumbra() {
+            diagnostics.push(Diagnostic::warning(format!(
+                "intervals grow faster than the {} branches or slower than the probe spacing, which breaks the penumbra condition",
+                self.branches()
+            )));
+        }
+        let diagonal = (world_size[0] as f32).hypot(world_size[1] as f32);
+        let reach = self.interval_end_host(self.num_cascades - 1);
+        if reach < diagonal {
+            diagnostics.push(Diagnostic::error(format!(
+                "the last interval ends at {}, which doesn't reach across the world diagonal of {}",
+                reach, diagonal
+            )));
+        }
+
+        diagnostics
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Value, Serialize, Deserialize)]
 pub struct CascadeSize {
@@ -115,29 +472,82 @@ mod to_glam {
     }
 }
 
+enum StorageBuffer<T: Value> {
+    Full(Buffer<T>),
+    Half(Buffer<Vec2<u32>>),
+    Rgb9e5(Buffer<u32>),
+}
+
 pub struct CascadeStorage<T: Value> {
     settings: CascadeSettings,
-    buffer: Buffer<T>,
+    /// The start of each level in the buffer, from [`CascadeSettings::level_offset`].
+    offsets: Buffer<u32>,
+    buffer: StorageBuffer<T>,
 }
-impl<T: Value> CascadeStorage<T> {
+impl<T: Packable> CascadeStorage<T> {
     pub fn settings(&self) -> &CascadeSettings {
         &self.settings
     }
     pub fn new(settings: CascadeSettings) -> Self {
-        let buffer =
-            DEVICE.create_buffer((settings.cascade_total_size() * settings.num_cascades) as usize);
-        Self { settings, buffer }
+        let offsets = DEVICE.create_buffer_from_fn(settings.num_cascades as usize, |level| {
+            settings.level_offset(level as u32)
+        });
+        let size = settings.cascade_total_size() as usize;
+        let buffer = match settings.format {
+            StorageFormat::Full => StorageBuffer::Full(DEVICE.create_buffer(size)),
+            StorageFormat::Half => StorageBuffer::Half(DEVICE.create_buffer(size)),
+            StorageFormat::Rgb9e5 => StorageBuffer::Rgb9e5(DEVICE.create_buffer(size)),
+        };
+        Self {
+            settings,
+            offsets,
+            buffer,
+        }
+    }
+    /// The size of a ray in bytes.
+    fn ray_bytes(&self) -> usize {
+        match self.buffer {
+            StorageBuffer::Full(_) => std::mem::size_of::<T>(),
+            _ => self.settings.format.ray_bytes(),
+        }
+    }
+    /// The size of a level in bytes.
+    pub fn level_bytes(&self, level: u32) -> usize {
+        self.settings.level_total_size(level) as usize * self.ray_bytes()
+    }
+    /// The size of all levels in bytes.
+    pub fn bytes(&self) -> usize {
+        self.settings.cascade_total_size() as usize * self.ray_bytes()
     }
-    #[tracked]
     pub fn to_index(&self, ray: Expr<RayLocation>) -> Expr<u32> {
-        let cascade_total_size = self.settings.cascade_total_size();
+        match self.settings.layout {
+            StorageLayout::ProbeMajor => self.probe_major_index(ray),
+            StorageLayout::DirectionMajor => self.direction_major_index(ray),
+            StorageLayout::Tiled(tile) => self.tiled_index(ray, tile),
+        }
+    }
+    #[tracked]
+    fn probe_major_index(&self, ray: Expr<RayLocation>) -> Expr<u32> {
         let linear_index = ray.probe.x + ray.probe.y * self.settings.probe_count(ray.level).x;
-        // Other way seems to run slightly slower.
-        ray.level * cascade_total_size
+        self.offsets.read(ray.level)
             + linear_index * self.settings.facing_count(ray.level)
-            + ray.facing // * self.settings.probe_count(ray.level).reduce_prod()
+            + ray.facing
+    }
+    #[tracked]
+    fn direction_major_index(&self, ray: Expr<RayLocation>) -> Expr<u32> {
+        let probe_count = self.settings.probe_count(ray.level);
+        let linear_index = ray.probe.x + ray.probe.y * probe_count.x;
+        self.offsets.read(ray.level) + ray.facing * probe_count.x * probe_count.y + linear_index
     }
     #[tracked]
+    fn tiled_index(&self, ray: Expr<RayLocation>, tile: u32) -> Expr<u32> {
+        let tiles_x = (self.settings.probe_count(ray.level).x + (tile - 1)) / tile;
+        let tile_index = ray.probe.x / tile + ray.probe.y / tile * tiles_x;
+        let local_index = ray.probe.x % tile + ray.probe.y % tile * tile;
+        self.offsets.read(ray.level)
+            + (tile_index * (tile * tile) + local_index) * self.settings.facing_count(ray.level)
+            + ray.facing
+    }
     pub fn read(&self, ray: Expr<RayLocation>) -> Expr<T> {
         // let a = ray.cascade < self.settings.num_cascades;
         // lc_assert!(a);
@@ -151,10 +561,20 @@ impl<T: Value> CascadeStorage<T> {
         //     < self.settings.base_size.directions << (ray.cascade * self.settings.angular_factor);
         // lc_assert!(d);
 
-        self.buffer.read(self.to_index(ray))
+        let index = self.to_index(ray);
+        match &self.buffer {
+            StorageBuffer::Full(buffer) => buffer.read(index),
+            StorageBuffer::Half(buffer) => T::unpack_half(buffer.read(index)),
+            StorageBuffer::Rgb9e5(buffer) => T::unpack_rgb9e5(buffer.read(index)),
+        }
     }
     pub fn write(&self, ray: Expr<RayLocation>, value: Expr<T>) {
-        self.buffer.write(self.to_index(ray), value);
+        let index = self.to_index(ray);
+        match &self.buffer {
+            StorageBuffer::Full(buffer) => buffer.write(index, value),
+            StorageBuffer::Half(buffer) => buffer.write(index, T::pack_half(value)),
+            StorageBuffer::Rgb9e5(buffer) => buffer.write(index, T::pack_rgb9e5(value)),
+        }
     }
 }
 
@@ -250,3 +670,148 @@ impl BilinearSamplesExpr {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::{assert_diagnostic, Severity};
+
+    const WORLD_SIZE: [u32; 2] = [512, 512];
+
+    fn cascades() -> CascadeSettings {
+        Settings::default().cascades
+    }
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(cascades().validate(WORLD_SIZE), vec![]);
+    }
+
+    #[test]
+    fn problems() {
+        let mut facings = cascades();
+        facings.base_size.facings = 6;
+        let mut no_facings = cascades();
+        no_facings.base_size.facings = 0;
+        let mut few_probes = cascades();
+        few_probes.base_size.probes = Vec2::new(256, 512);
+        let mut many_probes = cascades();
+        many_probes.base_size.probes = Vec2::new(520, 512);
+        let cases = [
+            (
+                CascadeSettings {
+                    num_cascades: 0,
+                    ..cascades()
+                },
+                "at least one cascade",
+                Severity::Error,
+            ),
+            (facings, "not divisible", Severity::Error),
+            (no_facings, "at least one facing", Severity::Error),
+            (few_probes, "only cover", Severity::Error),
+            (many_probes, "extend past", Severity::Warning),
+            (
+                CascadeSettings {
+                    layout: StorageLayout::Tiled(0),
+                    ..cascades()
+                },
+                "storage tiles",
+                Severity::Error,
+            ),
+            (
+                CascadeSettings {
+                    intervals: IntervalSchedule::Ends(vec![1.0, 4.0, 16.0]),
+                    ..cascades()
+                },
+                "interval ends",
+                Severity::Error,
+            ),
+            (
+                CascadeSettings {
+                    intervals: IntervalSchedule::Ends(vec![1.0, 4.0, 2.0, 64.0, 256.0, 1024.0]),
+                    ..cascades()
+                },
+                "interval of level 2",
+                Severity::Error,
+            ),
+            (
+                CascadeSettings {
+                    intervals: IntervalSchedule::Scaling(vec![8.0]),
+                    ..cascades()
+                },
+                "penumbra",
+                Severity::Warning,
+            ),
+            (
+                CascadeSettings {
+                    num_cascades: 4,
+                    ..cascades()
+                },
+                "world diagonal",
+                Severity::Error,
+            ),
+        ];
+        for (settings, message, severity) in cases {
+            assert_diagnostic(&settings.validate(WORLD_SIZE), message, severity);
+        }
+    }
+
+    #[test]
+    fn derived_cascades_are_valid() {
+        for world_size in [[1, 1], [640, 360], [4096, 16]] {
+            let diagonal = (world_size[0] as f32).hypot(world_size[1] as f32);
+            for quality in [Quality::Fast, Quality::Balanced, Quality::High] {
+                for settings in [
+                    CascadeSettings::display(world_size, quality),
+                    CascadeSettings::bounce(world_size, quality),
+                ] {
+                    let context = format!("{:?} at {:?}: {:?}", quality, world_size, settings);
+                    assert!(
+                        settings.interval_end_host(settings.num_cascades - 1) >= diagonal,
+                        "{}",
+                        context
+                    );
+                    assert!(settings.satisfies_penumbra(), "{}", context);
+                    assert_eq!(settings.validate(world_size), vec![], "{}", context);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn interval_ends_match_host() {
+        for intervals in [
+            IntervalSchedule::Geometric,
+            IntervalSchedule::Ends(vec![1.0, 3.0, 10.0, 40.0, 150.0, 800.0]),
+            IntervalSchedule::Scaling(vec![3.0, 5.0]),
+            IntervalSchedule::Scaling(vec![2.0; 8]),
+        ] {
+            let settings = CascadeSettings {
+                intervals,
+                ..cascades()
+            };
+            // Including the level past the top used by the parallax merge, and a few more.
+            let levels = settings.num_cascades + 4;
+            let ends = DEVICE.create_buffer::<f32>(levels as usize);
+            DEVICE
+                .create_kernel::<fn()>(&track!(|| {
+                    let level = dispatch_id().x;
+                    ends.write(level, settings.interval_end(level));
+                }))
+                .dispatch([levels, 1, 1]);
+            let mut gpu = vec![0.0; levels as usize];
+            ends.view(..).copy_to(&mut gpu);
+            for (level, gpu) in gpu.into_iter().enumerate() {
+                let host = settings.interval_end_host(level as u32);
+                assert!(
+                    (gpu - host).abs() <= host * 1e-5,
+                    "{:?} level {}: gpu {} != host {}",
+                    settings.intervals,
+                    level,
+                    gpu,
+                    host
+                );
+            }
+        }
+    }
+}
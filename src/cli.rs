@@ -0,0 +1,331 @@
+use cascade::Quality;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Args, Parser, Subcommand};
+use radiance::MergeVariant;
+
+use super::*;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "2D lighting using Radiance Cascades")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Open an interactive window to view and draw on a world (the default).
+    View(SceneArgs),
+    /// Light a world without opening a window, and write the radiance to disk.
+    Render(RenderArgs),
+    /// Brute-force the converged lighting of a world, for comparing against the cascades.
+    Truth(TruthArgs),
+    /// Compare a radiance TIFF against a reference, printing error metrics and writing a heatmap.
+    Compare(CompareArgs),
+    /// Convert an image into a world file using a palette.
+    Convert(ConvertArgs),
+    /// Time the merge kernels with different block sizes, and write the fastest as tuning settings.
+    #[cfg(feature = "trace")]
+    Tune(TuneArgs),
+    /// Check the settings for problems.
+    Check(SceneArgs),
+    /// Derive settings for a world size and quality, and print them or write them to a file.
+    Derive(DeriveArgs),
+    /// Print the sizes of the cascades and the inputs.
+    Info(SceneArgs),
+}
+
+#[derive(Debug, Default, Args)]
+pub struct SceneArgs {
+    /// The world to load: a layered TIFF, or an image if `--palette` is given.
+    /// If omitted, an empty world is used.
+    #[arg(short, long, value_parser = existing_file)]
+    pub world: Option<String>,
+    /// The environment map [default: env/default.tiff]
+    #[arg(short, long, value_parser = existing_file)]
+    pub env: Option<String>,
+    /// The settings file [default: settings/default.ron]
+    #[arg(short, long, value_parser = existing_file)]
+    pub settings: Option<String>,
+    /// Palette mapping image colors to materials, used to load the world from an image.
+    #[arg(short, long, value_parser = existing_file, requires = "world")]
+    pub palette: Option<String>,
+}
+impl SceneArgs {
+    pub fn world(&self) -> String {
+        self.world
+            .clone()
+            .unwrap_or_else(|| "world/default.tiff".to_string())
+    }
+    pub fn env(&self) -> String {
+        self.env
+            .clone()
+            .unwrap_or_else(|| "env/default.tiff".to_string())
+    }
+    pub fn settings(&self) -> String {
+        self.settings
+            .clone()
+            .unwrap_or_else(|| "settings/default.ron".to_string())
+    }
+    /// Loads and validates the settings, printing any problems found.
+    pub fn load_settings(&self) -> Result<Settings> {
+        load_settings(&self.settings())
+    }
+    pub fn load_palette(&self) -> Result<Option<Palette>> {
+        self.palette.as_ref().map(load_palette).transpose()
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct RenderArgs {
+    #[command(flatten)]
+    pub scene: SceneArgs,
+    /// Output path, without extension. Both `.tiff` (linear) and `.png` (tonemapped) are written.
+    /// [default: <world>-render]
+    #[arg(short, long)]
+    pub output: Option<String>,
+    /// Number of frames to run before saving, overriding `render_frames` from the settings.
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub frames: Option<u32>,
+    /// Also write every level of both cascades as TIFFs into this directory.
+    #[arg(long)]
+    pub dump_cascades: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct TruthArgs {
+    #[command(flatten)]
+    pub scene: SceneArgs,
+    /// Output path, without extension. Both `.tiff` (linear) and `.png` (tonemapped) are written.
+    /// [default: <world>-truth]
+    #[arg(short, long)]
+    pub output: Option<String>,
+    /// Number of frames to accumulate, each with a different ray offset.
+    #[arg(short, long, default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..))]
+    pub frames: u32,
+    /// Number of rays per pixel in each frame.
+    #[arg(short, long, default_value_t = 256, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rays: u32,
+}
+
+#[derive(Debug, Args)]
+pub struct CompareArgs {
+    /// The reference radiance, such as the output of `truth`.
+    #[arg(value_parser = existing_file)]
+    pub reference: String,
+    /// The radiance to compare, such as the output of `render`.
+    #[arg(value_parser = existing_file)]
+    pub image: String,
+    /// Output path of the error heatmap [default: <image>-error.png]
+    #[arg(short, long)]
+    pub output: Option<String>,
+    /// Error shown as the hottest color in the heatmap [default: the maximum error]
+    #[arg(long)]
+    pub scale: Option<f32>,
+}
+
+#[derive(Debug, Args)]
+pub struct TuneArgs {
+    #[command(flatten)]
+    pub scene: SceneArgs,
+    /// Output path of the tuning settings [default: <settings>-tuning.ron]
+    #[arg(short, long)]
+    pub output: Option<String>,
+    /// Total threads per block of the candidate block sizes.
+    /// Each has to be a power of two, with enough threads for the smallest blocks of any merge variant.
+    #[arg(short, long, num_args = 1.., default_values_t = [64, 128, 256], value_parser = parse_threads)]
+    pub threads: Vec<u32>,
+    /// Frames run before measuring each candidate.
+    #[arg(long, default_value_t = 5)]
+    pub warmup: u32,
+    /// Frames measured for each candidate.
+    #[arg(short, long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    pub frames: u32,
+}
+
+#[derive(Debug, Args)]
+pub struct DeriveArgs {
+    /// The size of the world, as `<width>x<height>`.
+    #[arg(long, value_parser = world_size)]
+    pub size: [u32; 2],
+    #[arg(short, long, default_value = "balanced", value_parser = quality())]
+    pub quality: Quality,
+    /// Output settings file. If omitted, the settings are printed instead.
+    #[arg(short, long)]
+    pub output: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ConvertArgs {
+    /// The image to convert.
+    #[arg(short, long, value_parser = existing_file)]
+    pub world: String,
+    /// Palette mapping image colors to materials.
+    #[arg(short, long, value_parser = existing_file)]
+    pub palette: String,
+    /// The settings file, used to find the materials [default: settings/default.ron]
+    #[arg(short, long, value_parser = existing_file)]
+    pub settings: Option<String>,
+    /// Output world file [default: <world>.tiff]
+    #[arg(short, long)]
+    pub output: Option<String>,
+}
+
+fn world_size(size: &str) -> Result<[u32; 2], String> {
+    let parse = |x: &str| x.trim().parse::<u32>().ok().filter(|&x| x > 0);
+    size.split_once('x')
+        .and_then(|(width, height)| Some([parse(width)?, parse(height)?]))
+        .ok_or_else(|| format!("`{}` is not a size like `640x360`", size))
+}
+
+/// Parses a quality by its name in the settings files.
+fn quality() -> impl TypedValueParser<Value = Quality> {
+    PossibleValuesParser::new(["fast", "balanced", "high"]).map(|quality| match quality.as_str() {
+        "fast" => Quality::Fast,
+        "high" => Quality::High,
+        _ => Quality::Balanced,
+    })
+}
+
+fn parse_threads(threads: &str) -> Result<u32, String> {
+    let min = MergeVariant::ALL
+        .map(|variant| {
+            RadianceCascades::min_block_size(variant)
+                .iter()
+                .product::<u32>()
+        })
+        .into_iter()
+        .min()
+        .unwrap();
+    threads
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .filter(|&x| x.is_power_of_two() && x >= min)
+        .ok_or_else(|| format!("`{}` is not a power of two of at least {}", threads, min))
+}
+
+fn existing_file(path: &str) -> Result<String, String> {
+    if Path::new(path).is_file() {
+        Ok(path.to_string())
+    } else {
+        Err(format!("file `{}` does not exist", path))
+    }
+}
+
+pub fn main() {
+    let cli = Cli::parse();
+    let result = match cli.command.unwrap_or(Command::View(SceneArgs::default())) {
+        Command::View(args) => app::run(args),
+        Command::Render(args) => render::run(args),
+        Command::Truth(args) => truth::run(args),
+        Command::Compare(args) => compare::run(args),
+        Command::Convert(args) => convert(args),
+        #[cfg(feature = "trace")]
+        Command::Tune(args) => tune::run(args),
+        Command::Check(args) => check(args),
+        Command::Derive(args) => derive(args),
+        Command::Info(args) => info(args),
+    };
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn convert(args: ConvertArgs) -> Result<()> {
+    let settings = load_settings(args.settings.as_deref().unwrap_or("settings/default.ron"))?;
+    let materials = load_materials(&settings.materials)?;
+    let palette = load_palette(&args.palette)?;
+
+    let (width, height) =
+        image::image_dimensions(&args.world).map_err(AmidaError::image(&args.world))?;
+    let world = World::new(width, height);
+    world.load_palette(args.world.as_str(), palette, &materials)?;
+
+    let output = args.output.unwrap_or_else(|| args.world.clone() + ".tiff");
+    world.save(output.as_str())?;
+    println!("Converted {} to {}", args.world, output);
+    Ok(())
+}
+
+fn check(args: SceneArgs) -> Result<()> {
+    args.load_settings()?;
+    println!("{}: no errors found", args.settings());
+    Ok(())
+}
+
+fn derive(args: DeriveArgs) -> Result<()> {
+    let settings = Settings::derive(args.size, args.quality);
+    let data = ron::ser::to_string_pretty(&settings, ron::ser::PrettyConfig::default())
+        .expect("Settings should always serialize");
+    match args.output {
+        Some(output) => {
+            std::fs::write(&output, data).map_err(AmidaError::io(&output))?;
+            println!("Wrote {:?} settings to {}", args.quality, output);
+            print_cascades("Display cascades", &settings.cascades);
+            print_cascades("Bounce cascades", &settings.bounce_cascades);
+        }
+        None => println!("{}", data),
+    }
+    Ok(())
+}
+
+fn print_cascades(name: &str, cascades: &CascadeSettings) {
+    println!("{}:", name);
+    let storage = cascades.storage_settings();
+    let bytes = |level| storage.level_total_size(level) as usize * cascades.format.ray_bytes();
+    let mut total_rays = 0;
+    for level in 0..cascades.num_cascades {
+        let size = cascades.level_size(level);
+        let rays = cascades.level_total_size(level);
+        total_rays += rays;
+        println!(
+            "  Level {}: {}x{} probes, {} facings, {} rays, {:.2} MiB",
+            level,
+            size.probes.x,
+            size.probes.y,
+            size.facings,
+            rays,
+            bytes(level) as f64 / (1 << 20) as f64
+        );
+    }
+    println!(
+        "  Total: {} rays, {:.2} MiB as {:?}",
+        total_rays,
+        (0..cascades.num_cascades).map(bytes).sum::<usize>() as f64 / (1 << 20) as f64,
+        cascades.format
+    );
+}
+
+fn info(args: SceneArgs) -> Result<()> {
+    let settings = args.load_settings()?;
+    println!("Settings: {}", args.settings());
+    println!(
+        "World size: {}x{}",
+        settings.world_size[0], settings.world_size[1]
+    );
+    print_cascades("Display cascades", &settings.cascades);
+    print_cascades("Bounce cascades", &settings.bounce_cascades);
+
+    let world = args.world();
+    if args.palette.is_some() {
+        let (width, height) = image::image_dimensions(&world).map_err(AmidaError::image(&world))?;
+        println!("World image: {} ({}x{})", world, width, height);
+    } else if Path::new(&world).is_file() {
+        let file = File::open(&world).map_err(AmidaError::io(&world))?;
+        let mut file = TiffDecoder::new(file).map_err(AmidaError::tiff(&world))?;
+        let (width, height) = file.dimensions().map_err(AmidaError::tiff(&world))?;
+        println!("World: {} ({}x{})", world, width, height);
+    } else {
+        println!("World: empty");
+    }
+
+    let env = args.env();
+    if Path::new(&env).is_file() {
+        println!("Environment: {} ({} facings)", env, load_env(&env)?.len());
+    } else {
+        println!("Environment: none");
+    }
+    Ok(())
+}
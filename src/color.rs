@@ -84,3 +84,81 @@ impl FluenceExpr {
         near.radiance + near.transmittance * far
     }
 }
+
+/// Values which can be stored in a packed [`StorageFormat`](crate::cascade::StorageFormat).
+pub trait Packable: Value {
+    fn pack_half(value: Expr<Self>) -> Expr<Vec2<u32>>;
+    fn unpack_half(packed: Expr<Vec2<u32>>) -> Expr<Self>;
+    fn pack_rgb9e5(value: Expr<Self>) -> Expr<u32>;
+    fn unpack_rgb9e5(packed: Expr<u32>) -> Expr<Self>;
+}
+
+/// `floor(log2(x))`, corrected for `log2` being approximate on some devices.
+#[tracked]
+fn exponent(x: Expr<f32>) -> Expr<f32> {
+    let exponent = x.log2().floor().var();
+    if x < exponent.exp2() {
+        *exponent -= 1.0;
+    } else if x >= (exponent + 1.0).exp2() {
+        *exponent += 1.0;
+    }
+    **exponent
+}
+
+/// Encodes a non-negative value as the bits of a half float, ignoring the sign.
+#[tracked]
+fn encode_half(x: Expr<f32>) -> Expr<u32> {
+    let x = x.clamp(0.0, 65504.0);
+    // Subnormals share the exponent of the smallest normal number.
+    let exponent = keter::max(exponent(x), -14.0);
+    let mantissa = (x * (10.0 - exponent).exp2()).round();
+    // The implicit leading one lands in the exponent bits, so rounding up to the next exponent carries over.
+    (mantissa + (exponent + 14.0) * 1024.0).cast_u32()
+}
+
+#[tracked]
+fn decode_half(bits: Expr<u32>) -> Expr<f32> {
+    let exponent = (bits >> 10) & 31;
+    let mantissa = bits & 1023;
+    if exponent == 0 {
+        mantissa.cast_f32() * (-24.0_f32).exp2()
+    } else {
+        (mantissa + 1024).cast_f32() * (exponent.cast_f32() - 25.0).exp2()
+    }
+}
+
+impl Packable for Radiance {
+    /// Three half floats, with the red and green in the first word.
+    #[tracked]
+    fn pack_half(value: Expr<Self>) -> Expr<Vec2<u32>> {
+        Vec2::expr(
+            encode_half(value.x) | (encode_half(value.y) << 16),
+            encode_half(value.z),
+        )
+    }
+    #[tracked]
+    fn unpack_half(packed: Expr<Vec2<u32>>) -> Expr<Self> {
+        Vec3::expr(
+            decode_half(packed.x & 0xffff),
+            decode_half(packed.x >> 16),
+            decode_half(packed.y),
+        )
+    }
+    /// Three 9 bit mantissas with a shared 5 bit exponent, as in `EXT_texture_shared_exponent`.
+    #[tracked]
+    fn pack_rgb9e5(value: Expr<Self>) -> Expr<u32> {
+        let value = value.clamp(0.0, 65408.0);
+        let max_component = value.reduce_max();
+        let exponent = (keter::max(exponent(max_component), -16.0) + 1.0).var();
+        if (max_component * (9.0 - exponent).exp2()).round() >= 512.0 {
+            *exponent += 1.0;
+        }
+        let mantissa = (value * (9.0 - exponent).exp2()).round().cast_u32();
+        mantissa.x | (mantissa.y << 9) | (mantissa.z << 18) | ((exponent + 15.0).cast_u32() << 27)
+    }
+    #[tracked]
+    fn unpack_rgb9e5(packed: Expr<u32>) -> Expr<Self> {
+        let mantissa = Vec3::expr(packed & 511, (packed >> 9) & 511, (packed >> 18) & 511);
+        mantissa.cast_f32() * ((packed >> 27).cast_f32() - 24.0).exp2()
+    }
+}
@@ -0,0 +1,160 @@
+use std::fmt::{self, Display, Formatter};
+
+use cli::CompareArgs;
+use image::{Rgb, RgbImage};
+use render::load_radiance;
+
+use super::*;
+
+/// Added to the denominator of the relative error to avoid dividing by zero in dark areas.
+const RELATIVE_EPSILON: f32 = 0.01;
+
+/// Error metrics of a single color channel.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ChannelMetrics {
+    pub rmse: f32,
+    /// Mean of `(image - reference)^2 / (reference^2 + 0.01)`.
+    pub relative_mse: f32,
+    /// Peak signal to noise ratio in decibels, using the maximum of the reference as the peak.
+    pub psnr: f32,
+    pub max_error: f32,
+}
+
+/// Error metrics of an image compared against a reference, per RGB channel.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Metrics {
+    pub channels: [ChannelMetrics; 3],
+}
+impl Metrics {
+    pub fn new(reference: &[FVec3], image: &[FVec3]) -> Self {
+        assert_eq!(reference.len(), image.len());
+        let n = reference.len().max(1) as f64;
+        let channels = std::array::from_fn(|c| {
+            let mut squared = 0.0_f64;
+            let mut relative = 0.0_f64;
+            let mut max_error = 0.0_f32;
+            let mut peak = 0.0_f32;
+            for (r, x) in reference.iter().zip(image) {
+                let (r, x) = (r[c], x[c]);
+                let error = (x - r).abs();
+                squared += (error * error) as f64;
+                relative += (error * error / (r * r + RELATIVE_EPSILON)) as f64;
+                max_error = max_error.max(error);
+                peak = peak.max(r);
+            }
+            let mse = squared / n;
+            ChannelMetrics {
+                rmse: mse.sqrt() as f32,
+                relative_mse: (relative / n) as f32,
+                psnr: (10.0 * (peak as f64 * peak as f64 / mse).log10()) as f32,
+                max_error,
+            }
+        });
+        Self { channels }
+    }
+    pub fn rmse(&self) -> f32 {
+        self.channels.iter().map(|c| c.rmse).fold(0.0, f32::max)
+    }
+    pub fn relative_mse(&self) -> f32 {
+        self.channels
+            .iter()
+            .map(|c| c.relative_mse)
+            .fold(0.0, f32::max)
+    }
+    pub fn max_error(&self) -> f32 {
+        self.channels
+            .iter()
+            .map(|c| c.max_error)
+            .fold(0.0, f32::max)
+    }
+}
+impl Display for Metrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>8} {:>12} {:>12} {:>10} {:>12}",
+            "channel", "rmse", "rel. mse", "psnr", "max error"
+        )?;
+        for (name, c) in ["red", "green", "blue"].into_iter().zip(&self.channels) {
+            writeln!(
+                f,
+                "{:>8} {:>12.6} {:>12.6} {:>7.2} dB {:>12.6}",
+                name, c.rmse, c.relative_mse, c.psnr, c.max_error
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// https://gist.github.com/mikhailov-work/0d177465a8151eb6ede1768d51d476c7
+#[allow(clippy::excessive_precision)]
+fn turbo(x: f32) -> FVec3 {
+    let x = x.clamp(0.0, 1.0);
+    let v4 = glam::Vec4::new(1.0, x, x * x, x * x * x);
+    let v2 = glam::Vec2::new(v4.z, v4.w) * v4.z;
+    FVec3::new(
+        v4.dot(glam::Vec4::new(
+            0.13572138,
+            4.61539260,
+            -42.66032258,
+            132.13108234,
+        )) + v2.dot(glam::Vec2::new(-152.94239396, 59.28637943)),
+        v4.dot(glam::Vec4::new(
+            0.09140261,
+            2.19418839,
+            4.84296658,
+            -14.18503333,
+        )) + v2.dot(glam::Vec2::new(4.27729857, 2.82956604)),
+        v4.dot(glam::Vec4::new(
+            0.10667330,
+            12.64194608,
+            -60.58204836,
+            110.36276771,
+        )) + v2.dot(glam::Vec2::new(-89.90310912, 27.34824973)),
+    )
+}
+
+/// False-color image of the per-pixel error, averaged over the channels.
+/// Errors of `scale` and above are shown as the hottest color.
+pub fn error_heatmap(reference: &[FVec3], image: &[FVec3], size: [u32; 2], scale: f32) -> RgbImage {
+    RgbImage::from_fn(size[0], size[1], |x, y| {
+        let i = (x + y * size[0]) as usize;
+        let error = (image[i] - reference[i]).abs().element_sum() / 3.0;
+        let color = turbo(error / scale.max(f32::MIN_POSITIVE)).clamp(FVec3::ZERO, FVec3::ONE);
+        Rgb(<[f32; 3]>::from(color * 255.0).map(|c| c.round() as u8))
+    })
+}
+
+/// Compares two radiance images, printing the metrics and writing an error heatmap.
+pub fn run(args: CompareArgs) -> Result<()> {
+    let (reference, size) = load_radiance(&args.reference)?;
+    let (image, image_size) = load_radiance(&args.image)?;
+    if image_size != size {
+        return Err(AmidaError::ReferenceSize {
+            path: PathBuf::from(&args.image),
+            expected: size,
+            found: image_size,
+        });
+    }
+
+    let metrics = Metrics::new(&reference, &image);
+    print!("{}", metrics);
+
+    let output = args.output.map(PathBuf::from).unwrap_or_else(|| {
+        let path = Path::new(&args.image);
+        let mut output_name = path.file_stem().unwrap().to_owned();
+        output_name.push("-error");
+        path.with_file_name(output_name)
+    });
+    let output = output.with_extension("png");
+    let scale = args.scale.unwrap_or_else(|| metrics.max_error());
+    error_heatmap(&reference, &image, size, scale)
+        .save(&output)
+        .map_err(AmidaError::image(&output))?;
+    println!(
+        "Error heatmap (scale {}) written to {}",
+        scale,
+        output.display()
+    );
+    Ok(())
+}
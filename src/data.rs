@@ -1,4 +1,7 @@
-use radiance::TuningSettings;
+use cascade::{BranchReduction, IntervalSchedule, Quality, StorageFormat, StorageLayout};
+use error::Diagnostic;
+use noise::NoiseSource;
+use radiance::{MergeSettings, TuningSettings};
 
 use super::*;
 
@@ -10,20 +13,91 @@ pub struct Settings {
     pub dpi: f64,
     pub bounce_cascades: CascadeSettings,
     pub bounce_tuning: TuningSettings,
+    pub bounce_merge: MergeSettings,
     pub cascades: CascadeSettings,
     pub display_tuning: TuningSettings,
-    pub merge_variant: usize,
+    pub display_merge: MergeSettings,
     pub num_bounces: usize,
+    pub render_frames: usize,
     pub paused: bool,
     pub run_final: bool,
     pub show_diff: bool,
+    pub show_overlay: bool,
     pub raw_radiance: bool,
+    pub accumulation: Option<f32>,
     pub display_level: u32,
     pub brush_radius: f32,
     pub draw_square: bool,
     pub materials: String,
     pub brushes: HashMap<BrushInput, Brush>,
 }
+impl Settings {
+    /// The default settings, with both cascades derived from the world size and quality.
+    pub fn derive(world_size: [u32; 2], quality: Quality) -> Self {
+        let defaults = Self::default();
+        let cascades = CascadeSettings::display(world_size, quality);
+        let bounce_cascades = CascadeSettings::bounce(world_size, quality);
+        Self {
+            world_size,
+            display_tuning: defaults.display_tuning.extended(cascades.num_cascades),
+            bounce_tuning: defaults
+                .bounce_tuning
+                .extended(bounce_cascades.num_cascades),
+            cascades,
+            bounce_cascades,
+            ..defaults
+        }
+    }
+    /// Validates both cascades with their tuning and merge variants.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for (name, cascades, tuning, merge) in [
+            (
+                "display cascades",
+                &self.cascades,
+                &self.display_tuning,
+                &self.display_merge,
+            ),
+            (
+                "bounce cascades",
+                &self.bounce_cascades,
+                &self.bounce_tuning,
+                &self.bounce_merge,
+            ),
+        ] {
+            let context = |diagnostic: Diagnostic| diagnostic.context(name);
+            diagnostics.extend(cascades.validate(self.world_size).into_iter().map(context));
+            diagnostics.extend(
+                tuning
+                    .validate(cascades.num_cascades)
+                    .into_iter()
+                    .map(context),
+            );
+            diagnostics.extend(
+                merge
+                    .validate(cascades.num_cascades)
+                    .into_iter()
+                    .map(context),
+            );
+        }
+        if let Some(weight) = self.accumulation {
+            if !(weight > 0.0 && weight <= 1.0) {
+                diagnostics.push(Diagnostic::error(format!(
+                    "accumulation weight {} is not in (0, 1]",
+                    weight
+                )));
+            }
+        }
+        if self.display_level >= self.cascades.num_cascades {
+            diagnostics.push(Diagnostic::error(format!(
+                "display level {} is not one of the {} display cascades",
+                self.display_level, self.cascades.num_cascades
+            )));
+        }
+        diagnostics
+    }
+}
+
 impl Default for Settings {
     fn default() -> Self {
         Self {
@@ -41,6 +115,11 @@ impl Default for Settings {
                 spatial_factor: 1,
                 angular_factor: 2,
                 length_scaling: 4.0,
+                intervals: IntervalSchedule::Geometric,
+                layout: StorageLayout::ProbeMajor,
+                format: StorageFormat::Full,
+                noise: NoiseSource::White,
+                reduction: BranchReduction::Shared,
             },
             bounce_tuning: TuningSettings {
                 block_sizes: [
@@ -51,6 +130,8 @@ impl Default for Settings {
                 .into_iter()
                 .collect(),
             },
+            // No observable difference between variants, so use the cheapest one.
+            bounce_merge: MergeSettings::default(),
             cascades: CascadeSettings {
                 base_interval: (0.0, 1.0),
                 base_probe_spacing: 1.0,
@@ -62,6 +143,11 @@ impl Default for Settings {
                 spatial_factor: 1,
                 angular_factor: 2,
                 length_scaling: 4.0,
+                intervals: IntervalSchedule::Geometric,
+                layout: StorageLayout::ProbeMajor,
+                format: StorageFormat::Full,
+                noise: NoiseSource::White,
+                reduction: BranchReduction::Shared,
             },
             display_tuning: TuningSettings {
                 block_sizes: [
@@ -72,12 +158,15 @@ impl Default for Settings {
                 .into_iter()
                 .collect(),
             },
-            merge_variant: 0,
+            display_merge: MergeSettings::default(),
             num_bounces: 0,
+            render_frames: 1,
             paused: false,
             run_final: true,
             show_diff: false,
+            show_overlay: false,
             raw_radiance: false,
+            accumulation: None,
             display_level: 0,
             brush_radius: 5.0,
             draw_square: false,
@@ -255,3 +344,97 @@ pub struct Material {
 
 pub type Materials = HashMap<String, Material>;
 pub type Palette = HashMap<String, Brush>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::{assert_diagnostic, Severity};
+    use radiance::MergeVariant;
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(Settings::default().validate(), vec![]);
+    }
+
+    #[test]
+    fn derived_settings_are_valid() {
+        for world_size in [[1, 1], [640, 360], [4096, 16]] {
+            for quality in [Quality::Fast, Quality::Balanced, Quality::High] {
+                let settings = Settings::derive(world_size, quality);
+                assert_eq!(
+                    settings.validate(),
+                    vec![],
+                    "{:?} at {:?}",
+                    quality,
+                    world_size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn problems() {
+        let mut no_bounce_cascades = Settings::default();
+        no_bounce_cascades.bounce_cascades.num_cascades = 0;
+        let mut missing_tuning = Settings::default();
+        missing_tuning.display_tuning = TuningSettings::uniform([64, 1, 1], 3);
+        let mut merge_past_levels = Settings::default();
+        merge_past_levels
+            .display_merge
+            .levels
+            .insert(vec![8], MergeVariant::Nearest);
+        let mut merge_overlap = Settings::default();
+        merge_overlap
+            .display_merge
+            .levels
+            .insert(vec![0, 1], MergeVariant::Nearest);
+        merge_overlap
+            .display_merge
+            .levels
+            .insert(vec![1], MergeVariant::Diagonal);
+        let mut cases = vec![
+            (
+                no_bounce_cascades,
+                "bounce cascades: there must be at least one cascade",
+                Severity::Error,
+            ),
+            (
+                missing_tuning,
+                "display cascades: no block size",
+                Severity::Error,
+            ),
+            (merge_past_levels, "past the 6 levels", Severity::Warning),
+            (merge_overlap, "level 1 is overridden", Severity::Error),
+            (
+                Settings {
+                    display_level: 6,
+                    ..Default::default()
+                },
+                "display level 6",
+                Severity::Error,
+            ),
+        ];
+        for weight in [0.0, -0.5, 1.5, f32::NAN] {
+            cases.push((
+                Settings {
+                    accumulation: Some(weight),
+                    ..Default::default()
+                },
+                "accumulation weight",
+                Severity::Error,
+            ));
+        }
+        for (settings, message, severity) in cases {
+            assert_diagnostic(&settings.validate(), message, severity);
+        }
+    }
+
+    #[test]
+    fn full_accumulation_weight_is_valid() {
+        let settings = Settings {
+            accumulation: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(settings.validate(), vec![]);
+    }
+}
@@ -0,0 +1,268 @@
+use std::fmt::{self, Display, Formatter};
+
+use tiff::TiffError;
+
+use super::*;
+
+pub type Result<T, E = AmidaError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum AmidaError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Tiff {
+        path: PathBuf,
+        source: TiffError,
+    },
+    Image {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    Ron {
+        path: PathBuf,
+        source: ron::error::SpannedError,
+    },
+    UnsupportedColorType {
+        path: PathBuf,
+        layer: Option<String>,
+        color_type: ColorType,
+    },
+    WrongDimensions {
+        path: PathBuf,
+        layer: Option<String>,
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    WrongLayer {
+        path: PathBuf,
+        expected: String,
+        found: String,
+    },
+    MissingLayer {
+        path: PathBuf,
+        layer: String,
+    },
+    InvalidColor {
+        path: PathBuf,
+        color: String,
+    },
+    UnknownColor {
+        path: PathBuf,
+        color: [u8; 4],
+        position: (u32, u32),
+    },
+    UnknownMaterial {
+        material: String,
+        /// Where the material was referenced from, such as a brush or a palette entry.
+        context: String,
+    },
+    /// A brush or palette entry with no materials to pick from.
+    EmptyBrush {
+        /// The brush or palette entry, like in `UnknownMaterial`.
+        context: String,
+    },
+    /// The settings failed validation. The diagnostics have already been printed.
+    InvalidSettings {
+        path: PathBuf,
+        errors: usize,
+    },
+    /// An image doesn't match the size of the reference it is being compared to.
+    ReferenceSize {
+        path: PathBuf,
+        expected: [u32; 2],
+        found: [u32; 2],
+    },
+}
+
+impl AmidaError {
+    pub fn io(path: impl AsRef<Path>) -> impl FnOnce(std::io::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        |source| Self::Io { path, source }
+    }
+    pub fn tiff(path: impl AsRef<Path>) -> impl FnOnce(TiffError) -> Self {
+        let path = path.as_ref().to_path_buf();
+        |source| Self::Tiff { path, source }
+    }
+    pub fn image(path: impl AsRef<Path>) -> impl FnOnce(image::ImageError) -> Self {
+        let path = path.as_ref().to_path_buf();
+        |source| Self::Image { path, source }
+    }
+    pub fn ron(path: impl AsRef<Path>) -> impl FnOnce(ron::error::SpannedError) -> Self {
+        let path = path.as_ref().to_path_buf();
+        |source| Self::Ron { path, source }
+    }
+}
+
+impl Display for AmidaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Self::Tiff { path, source } => write!(f, "{}: {}", path.display(), source),
+            Self::Image { path, source } => write!(f, "{}: {}", path.display(), source),
+            Self::Ron { path, source } => write!(f, "{}:{}", path.display(), source),
+            Self::UnsupportedColorType {
+                path,
+                layer,
+                color_type,
+            } => {
+                write!(f, "{}: ", path.display())?;
+                if let Some(layer) = layer {
+                    write!(f, "layer `{}` has ", layer)?;
+                }
+                write!(
+                    f,
+                    "unsupported color type {:?}, expected 32-bit float RGB or RGBA",
+                    color_type
+                )
+            }
+            Self::WrongDimensions {
+                path,
+                layer,
+                expected,
+                found,
+            } => {
+                write!(f, "{}: ", path.display())?;
+                if let Some(layer) = layer {
+                    write!(f, "layer `{}` ", layer)?;
+                }
+                write!(
+                    f,
+                    "is {}x{}, but the world is {}x{}",
+                    found.0, found.1, expected.0, expected.1
+                )
+            }
+            Self::WrongLayer {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: expected layer `{}`, found `{}`",
+                path.display(),
+                expected,
+                found
+            ),
+            Self::MissingLayer { path, layer } => {
+                write!(f, "{}: missing layer `{}`", path.display(), layer)
+            }
+            Self::InvalidColor { path, color } => {
+                write!(f, "{}: invalid color `{}`", path.display(), color)
+            }
+            Self::UnknownColor {
+                path,
+                color: [r, g, b, a],
+                position: (x, y),
+            } => write!(
+                f,
+                "{}: color #{:02x}{:02x}{:02x}{:02x} at ({}, {}) is not in the palette",
+                path.display(),
+                r,
+                g,
+                b,
+                a,
+                x,
+                y
+            ),
+            Self::UnknownMaterial { material, context } => {
+                write!(f, "unknown material `{}` in {}", material, context)
+            }
+            Self::EmptyBrush { context } => write!(f, "{} has no materials", context),
+            Self::InvalidSettings { path, errors } => write!(
+                f,
+                "{}: found {} error{} in the settings",
+                path.display(),
+                errors,
+                if *errors == 1 { "" } else { "s" }
+            ),
+            Self::ReferenceSize {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: is {}x{}, but the reference is {}x{}",
+                path.display(),
+                found[0],
+                found[1],
+                expected[0],
+                expected[1]
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AmidaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Tiff { source, .. } => Some(source),
+            Self::Image { source, .. } => Some(source),
+            Self::Ron { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Works, but probably not as intended.
+    Warning,
+    /// Would crash or render garbage.
+    Error,
+}
+
+/// A problem found when validating the settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+impl Diagnostic {
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+    /// Adds what the diagnostic is about to the start of the message.
+    pub fn context(self, context: &str) -> Self {
+        Self {
+            message: format!("{}: {}", context, self.message),
+            ..self
+        }
+    }
+}
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// Asserts that one of the diagnostics has the severity and contains the message.
+#[cfg(test)]
+#[track_caller]
+pub(crate) fn assert_diagnostic(diagnostics: &[Diagnostic], message: &str, severity: Severity) {
+    assert!(
+        diagnostics
+            .iter()
+            .any(|d| d.severity == severity && d.message.contains(message)),
+        "no {:?} containing `{}` in {:?}",
+        severity,
+        message,
+        diagnostics
+    );
+}
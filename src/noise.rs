@@ -0,0 +1,93 @@
+pub use amida_reference::noise::{blue_noise, NoiseSource, BLUE_NOISE_SIZE, R2};
+use utils::{pcg3d, pcg3df};
+
+use super::*;
+
+/// The random numbers for the stochastic merge, from a [`NoiseSource`].
+pub struct Noise {
+    source: NoiseSource,
+    texture: Buffer<f32>,
+}
+impl Noise {
+    pub fn new(source: NoiseSource) -> Self {
+        let texture = match source {
+            NoiseSource::BlueNoise => DEVICE.create_buffer_from_slice(blue_noise()),
+            _ => DEVICE.create_buffer_from_slice(&[0.0]),
+        };
+        Self { source, texture }
+    }
+    /// Two random numbers in `[0, 1)` for a ray. Each level and facing uses a different spatial offset
+    /// so that they are not correlated, and each frame a different temporal offset.
+    pub fn sample2(
+        &self,
+        probe: Expr<Vec2<u32>>,
+        facing: Expr<u32>,
+        level: Expr<u32>,
+        frame: Expr<u32>,
+    ) -> Expr<Vec2<f32>> {
+        match self.source {
+            NoiseSource::White => self.white(probe, facing, level, frame),
+            NoiseSource::BlueNoise => self.blue_noise(probe, facing, level, frame),
+            NoiseSource::InterleavedGradient => {
+                self.interleaved_gradient(probe, facing, level, frame)
+            }
+        }
+    }
+    #[tracked]
+    fn white(
+        &self,
+        probe: Expr<Vec2<u32>>,
+        facing: Expr<u32>,
+        level: Expr<u32>,
+        frame: Expr<u32>,
+    ) -> Expr<Vec2<f32>> {
+        pcg3df(Vec3::expr(
+            facing + (frame << 16),
+            probe.x,
+            probe.y + (level << 16),
+        ))
+        .xy()
+    }
+    #[tracked]
+    fn blue_noise(
+        &self,
+        probe: Expr<Vec2<u32>>,
+        facing: Expr<u32>,
+        level: Expr<u32>,
+        frame: Expr<u32>,
+    ) -> Expr<Vec2<f32>> {
+        let offsets = pcg3d(Vec3::expr(facing, level, 0));
+        let read = |offset: Expr<u32>| {
+            let x = (probe.x + offset) % BLUE_NOISE_SIZE;
+            let y = (probe.y + (offset >> 16)) % BLUE_NOISE_SIZE;
+            self.texture.read(x + y * BLUE_NOISE_SIZE)
+        };
+        let value = Vec2::expr(read(offsets.x), read(offsets.y))
+            + Vec2::expr(frame * R2[0], frame * R2[1]).cast_f32() / 4294967296.0;
+        value - value.floor()
+    }
+    /// Jimenez's interleaved gradient noise, moved by 5.588238 pixels each frame.
+    #[tracked]
+    fn interleaved_gradient(
+        &self,
+        probe: Expr<Vec2<u32>>,
+        facing: Expr<u32>,
+        level: Expr<u32>,
+        frame: Expr<u32>,
+    ) -> Expr<Vec2<f32>> {
+        let offsets = pcg3d(Vec3::expr(facing, level, 0));
+        let offsets = Vec3::expr(offsets.x & 1023, offsets.y & 1023, offsets.z & 1023).cast_f32();
+        let time = (frame % 64).cast_f32() * 5.588238;
+        let gradient = |x: Expr<f32>, y: Expr<f32>| {
+            let x = 0.06711056 * x + 0.00583715 * y;
+            let x = 52.9829189 * (x - x.floor());
+            x - x.floor()
+        };
+        let x = probe.x.cast_f32() + time;
+        let y = probe.y.cast_f32() + time;
+        Vec2::expr(
+            gradient(x + offsets.x, y + offsets.y),
+            gradient(x + offsets.y, y + offsets.z),
+        )
+    }
+}
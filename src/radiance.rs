@@ -1,25 +1,184 @@
 use std::ops::Mul;
 
+use keter::lang::{
+    functions::{sync_block, thread_id, warp_lane_id, warp_read_lane, warp_size},
+    types::shared::Shared,
+};
+
 use super::*;
 use cascade::*;
+use error::Diagnostic;
+use noise::Noise;
 use trace::trace_radiance;
-use utils::pcg3df;
 
 mod bilinear_fix;
 mod diagonal;
 mod nearest;
+mod parallax;
 mod single_stochastic;
 
+pub use amida_reference::MergeVariant;
+
 fn zip3(a: [u32; 3], b: [u32; 3], f: impl Fn(u32, u32) -> u32) -> [u32; 3] {
     [f(a[0], b[0]), f(a[1], b[1]), f(a[2], b[2])]
 }
 
 struct MergeFunction {
-    function: fn(&TraceWorld, CascadeSettings, &CascadeStorage<Radiance>, Expr<u32>),
+    function:
+        fn(&TraceWorld, &CascadeSettings, &CascadeStorage<Radiance>, &Noise, Expr<u32>, Expr<u32>),
     dispatch_scaling: [u32; 3],
     min_block_size: [u32; 3],
 }
 
+/// Sums `value` over each group of `group` consecutive threads along x, giving every thread the sum of its group.
+/// `group` has to be a power of two, and the block width a multiple of it, so that groups don't span probes.
+fn sum_group(reduction: BranchReduction, value: Expr<Radiance>, group: u32) -> Expr<Radiance> {
+    if reduction == BranchReduction::Warp && block_size()[0] % group == 0 {
+        warp_or_shared_sum(value, group)
+    } else {
+        shared_sum(value, group)
+    }
+}
+
+/// Uses warp shuffles if the warps of the device are at least as wide as a group, and shared memory otherwise,
+/// as the warp size depends on the backend and GPU.
+#[tracked]
+fn warp_or_shared_sum(value: Expr<Radiance>, group: u32) -> Expr<Radiance> {
+    let total = value.var();
+    if warp_size() >= group {
+        *total = warp_sum(value, group);
+    } else {
+        *total = shared_sum(value, group);
+    }
+    **total
+}
+
+fn warp_sum(value: Expr<Radiance>, group: u32) -> Expr<Radiance> {
+    // Groups are aligned to their size so they never span warps, and swapping with
+    // the lane differing in each bit below the group size visits every lane of the group.
+    (0..group.trailing_zeros()).fold(value, |total, bit| {
+        total + warp_read_lane(total, warp_lane_id() ^ (1 << bit))
+    })
+}
+
+fn shared_sum(value: Expr<Radiance>, group: u32) -> Expr<Radiance> {
+    let shared = Shared::<Radiance>::new(block_size().iter().product::<u32>() as usize);
+    let block_offset = block_size()[0] * (thread_id().y + block_size()[1] * thread_id().z);
+    shared.write(thread_id().x + block_offset, value);
+    sync_block();
+    let first = thread_id().x - thread_id().x % group + block_offset;
+    (0..group)
+        .map(|i| shared.read(first + i))
+        .reduce(AddExpr::add)
+        .unwrap()
+}
+
+/// Averages the branches of each stored facing of the probe, where every branch is `scaling` consecutive
+/// threads along x whose values are already weighted, and writes the result from the first thread.
+#[tracked]
+fn write_average(
+    settings: &CascadeSettings,
+    radiance: &CascadeStorage<Radiance>,
+    value: Expr<Radiance>,
+    probe: Expr<Vec2<u32>>,
+    level: Expr<u32>,
+    scaling: u32,
+) {
+    let group = scaling * settings.branches();
+    let total = sum_group(settings.reduction, value, group);
+    if dispatch_id().x % group == 0 {
+        radiance.write(
+            RayLocation::from_comps_expr(RayLocationComps {
+                probe,
+                facing: dispatch_id().x / group,
+                level,
+            }),
+            total / settings.branches() as f32,
+        );
+    }
+}
+
+fn merge_function(variant: MergeVariant) -> MergeFunction {
+    match variant {
+        MergeVariant::Stochastic => MergeFunction {
+            function: single_stochastic::merge,
+            dispatch_scaling: [1, 1, 1],
+            min_block_size: [4, 1, 1],
+        },
+        MergeVariant::Nearest => MergeFunction {
+            function: nearest::merge,
+            dispatch_scaling: [1, 1, 1],
+            min_block_size: [4, 1, 1],
+        },
+        MergeVariant::BilinearFix => MergeFunction {
+            function: bilinear_fix::merge,
+            dispatch_scaling: [4, 1, 1],
+            min_block_size: [16, 1, 1],
+        },
+        MergeVariant::Diagonal => MergeFunction {
+            function: diagonal::merge,
+            dispatch_scaling: [2, 1, 1],
+            min_block_size: [8, 1, 1],
+        },
+        MergeVariant::Parallax => MergeFunction {
+            function: parallax::merge,
+            dispatch_scaling: [4, 1, 1],
+            min_block_size: [16, 1, 1],
+        },
+    }
+}
+
+/// The merge variant of each level of a cascade.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct MergeSettings {
+    pub variant: MergeVariant,
+    /// Variants for some levels which override `variant`, such as the bilinear fix on the lowest levels only.
+    #[serde(default)]
+    pub levels: HashMap<Vec<u32>, MergeVariant>,
+}
+impl MergeSettings {
+    /// Uses the same variant for every level.
+    pub fn uniform(variant: MergeVariant) -> Self {
+        Self {
+            variant,
+            levels: HashMap::new(),
+        }
+    }
+    pub fn variant(&self, level: u32) -> MergeVariant {
+        self.levels
+            .iter()
+            .find(|(levels, _)| levels.contains(&level))
+            .map_or(self.variant, |(_, variant)| *variant)
+    }
+    /// Checks that the overrides refer to existing levels, and don't conflict.
+    pub fn validate(&self, num_cascades: u32) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for level in self.levels.keys().flatten() {
+            if *level >= num_cascades {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "merge variant override for level {}, which is past the {} levels",
+                    level, num_cascades
+                )));
+            }
+        }
+        for level in 0..num_cascades {
+            let variants = self
+                .levels
+                .iter()
+                .filter(|(levels, _)| levels.contains(&level))
+                .map(|(_, variant)| *variant)
+                .collect::<Vec<_>>();
+            if variants.len() > 1 {
+                diagnostics.push(Diagnostic::error(format!(
+                    "level {} is overridden with more than one merge variant: {:?}",
+                    level, variants
+                )));
+            }
+        }
+        diagnostics
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct TuningSettings {
     pub block_sizes: HashMap<Vec<u32>, [u32; 3]>,
@@ -29,6 +188,58 @@ struct LoadedTuningSettings {
     assignments: Vec<u32>,
 }
 impl TuningSettings {
+    /// Uses the same block size for every level.
+    pub fn uniform(block_size: [u32; 3], num_cascades: u32) -> Self {
+        Self {
+            block_sizes: [((0..num_cascades).collect(), block_size)]
+                .into_iter()
+                .collect(),
+        }
+    }
+    /// Assigns any levels up to `num_cascades` that are missing a block size to the block size of the highest level.
+    pub fn extended(mut self, num_cascades: u32) -> Self {
+        let Some(highest) = self
+            .block_sizes
+            .keys()
+            .filter(|levels| !levels.is_empty())
+            .max_by_key(|levels| levels.iter().max())
+            .cloned()
+        else {
+            return self;
+        };
+        let missing = (0..num_cascades)
+            .filter(|level| !self.block_sizes.keys().any(|levels| levels.contains(level)))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            let block_size = self.block_sizes.remove(&highest).unwrap();
+            let mut levels = highest;
+            levels.extend(missing);
+            self.block_sizes.insert(levels, block_size);
+        }
+        self
+    }
+    /// Checks that every level has a block size.
+    pub fn validate(&self, num_cascades: u32) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let missing = (0..num_cascades)
+            .filter(|level| !self.block_sizes.keys().any(|levels| levels.contains(level)))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            diagnostics.push(Diagnostic::error(format!(
+                "no block size is assigned to levels {:?}",
+                missing
+            )));
+        }
+        for block_size in self.block_sizes.values() {
+            if block_size.contains(&0) {
+                diagnostics.push(Diagnostic::error(format!(
+                    "block size {:?} has no threads",
+                    block_size
+                )));
+            }
+        }
+        diagnostics
+    }
     fn load(self, num_cascades: u32) -> LoadedTuningSettings {
         let mut assignments = vec![u32::MAX; num_cascades as usize];
         let mut block_sizes = Vec::new();
@@ -53,98 +264,137 @@ impl TuningSettings {
 }
 
 struct MergeKernel {
-    kernels: Vec<keter::runtime::Kernel<fn(u32)>>,
+    kernels: Vec<keter::runtime::Kernel<fn(u32, u32)>>,
     dispatch_scaling: [u32; 3],
+    min_block_size: [u32; 3],
 }
 
 pub struct RadianceCascades {
+    /// Prefixed to the names of the merge commands, to tell the cascades apart when timing them.
+    name: &'static str,
     settings: CascadeSettings,
     pub radiance: CascadeStorage<Radiance>,
+    // Only read by the kernels.
+    #[allow(dead_code)]
+    noise: Noise,
     tuning: LoadedTuningSettings,
     merge_kernels: Vec<MergeKernel>,
 }
 
 impl RadianceCascades {
-    pub fn new(settings: CascadeSettings, world: &TraceWorld, tuning: TuningSettings) -> Self {
+    pub fn new(
+        name: &'static str,
+        settings: CascadeSettings,
+        world: &TraceWorld,
+        tuning: TuningSettings,
+    ) -> Self {
         assert_eq!(settings.base_size.facings % settings.branches(), 0, "The amount of facings must be divisible by the amount of branches for prefiltering to work");
-        let radiance = CascadeStorage::new(CascadeSettings {
-            base_size: CascadeSize {
-                facings: settings.base_size.facings / settings.branches(),
-                ..settings.base_size
-            },
-            ..settings
-        });
+        let radiance = CascadeStorage::new(settings.storage_settings());
+        let noise = Noise::new(settings.noise);
 
         let tuning = tuning.load(settings.num_cascades);
 
-        let merge_fns = vec![
-            MergeFunction {
-                function: single_stochastic::merge,
-                dispatch_scaling: [1, 1, 1],
-                min_block_size: [4, 1, 1],
-            },
-            MergeFunction {
-                function: nearest::merge,
-                dispatch_scaling: [1, 1, 1],
-                min_block_size: [4, 1, 1],
-            },
-            MergeFunction {
-                function: bilinear_fix::merge,
-                dispatch_scaling: [4, 1, 1],
-                min_block_size: [16, 1, 1],
-            },
-            MergeFunction {
-                function: diagonal::merge,
-                dispatch_scaling: [2, 1, 1],
-                min_block_size: [8, 1, 1],
-            },
-        ];
-
-        let merge_kernels = merge_fns
+        // Indexed by `MergeVariant as usize`, so any level can use any variant.
+        let merge_kernels = MergeVariant::ALL
+            .map(merge_function)
             .into_iter()
             .map(|merge| MergeKernel {
                 kernels: tuning
                     .block_sizes
                     .iter()
                     .map(|block_size| {
-                        DEVICE.create_kernel_async::<fn(u32)>(&|level| {
+                        DEVICE.create_kernel_async::<fn(u32, u32)>(&|level, frame| {
                             set_block_size(zip3(*block_size, merge.min_block_size, Ord::max));
-                            (merge.function)(world, settings, &radiance, level);
+                            (merge.function)(world, &settings, &radiance, &noise, level, frame);
                         })
                     })
                     .collect::<Vec<_>>(),
                 dispatch_scaling: merge.dispatch_scaling,
+                min_block_size: merge.min_block_size,
             })
             .collect::<Vec<_>>();
 
         Self {
+            name,
             settings,
             radiance,
+            noise,
             tuning,
             merge_kernels,
         }
     }
-    pub fn merge_kernel_count(&self) -> usize {
-        self.merge_kernels.len()
+    pub fn settings(&self) -> &CascadeSettings {
+        &self.settings
+    }
+    /// The smallest block size the merge variant can run with; smaller block sizes are rounded up to this.
+    pub fn min_block_size(variant: MergeVariant) -> [u32; 3] {
+        merge_function(variant).min_block_size
     }
-    pub fn update(&self, variant: usize) -> impl AsNodes {
+    /// Copies the stored radiance of every level back to the host, decoding any packed format.
+    /// Each level is in order of facing, then probe row, then probe column, whatever the storage layout.
+    pub fn read_levels(&self) -> Vec<Vec<FVec3>> {
+        let storage = self.radiance.settings();
+        let max_size = (0..storage.num_cascades)
+            .map(|level| storage.level_total_size(level))
+            .max()
+            .unwrap_or(0);
+        let staging_buffer = DEVICE.create_buffer::<f32>(3 * max_size.max(1) as usize);
+        let staging_kernel = DEVICE.create_kernel::<fn(u32)>(&track!(|level| {
+            let probe = dispatch_id().xy();
+            let facing = dispatch_id().z;
+            let probes = dispatch_size().xy();
+            let index = 3 * (probe.x + probes.x * (probe.y + probes.y * facing));
+            let value = self
+                .radiance
+                .read(RayLocation::from_comps_expr(RayLocationComps {
+                    probe,
+                    facing,
+                    level,
+                }));
+            staging_buffer.write(index, value.x);
+            staging_buffer.write(index + 1, value.y);
+            staging_buffer.write(index + 2, value.z);
+        }));
+        (0..storage.num_cascades)
+            .map(|level| {
+                let size = storage.level_size(level);
+                let count = (size.probes.x * size.probes.y * size.facings) as usize;
+                let mut staging_host = vec![0.0_f32; 3 * count];
+                (
+                    staging_kernel
+                        .dispatch_async([size.probes.x, size.probes.y, size.facings], &level),
+                    staging_buffer
+                        .view(..3 * count)
+                        .copy_to_async(&mut staging_host),
+                )
+                    .chain()
+                    .execute();
+                staging_host
+                    .chunks_exact(3)
+                    .map(|x| FVec3::new(x[0], x[1], x[2]))
+                    .collect()
+            })
+            .collect()
+    }
+    /// Merges every level from the top down. The frame seeds the stochastic variants.
+    pub fn update(&self, merge: &MergeSettings, frame: u32) -> impl AsNodes {
         let mut commands = vec![];
 
-        let merge = &self.merge_kernels[variant];
-
         for level in (0..self.settings.num_cascades).rev() {
+            let kernel = &self.merge_kernels[merge.variant(level) as usize];
             let level_size = self.settings.level_size(level);
             commands.push(
-                merge.kernels[self.tuning.assignments[level as usize] as usize]
+                kernel.kernels[self.tuning.assignments[level as usize] as usize]
                     .dispatch_async(
                         zip3(
                             [level_size.facings, level_size.probes.x, level_size.probes.y],
-                            merge.dispatch_scaling,
+                            kernel.dispatch_scaling,
                             Mul::mul,
                         ),
                         &level,
+                        &frame,
                     )
-                    .debug(format!("merge level {}", level)),
+                    .debug(format!("{} merge level {}", self.name, level)),
             );
         }
         commands.chain()
@@ -1,17 +1,14 @@
-use keter::lang::{
-    functions::{sync_block, thread_id},
-    types::shared::Shared,
-};
-
 use super::*;
 
 #[allow(unused)]
 #[tracked]
 pub fn merge(
     world: &TraceWorld,
-    settings: CascadeSettings,
+    settings: &CascadeSettings,
     radiance: &CascadeStorage<Radiance>,
+    noise: &Noise,
     level: Expr<u32>,
+    frame: Expr<u32>,
 ) {
     let probe = dispatch_id().yz();
     let facing = dispatch_id().x / 2;
@@ -49,47 +46,11 @@ pub fn merge(
     let next_radiance = if next_level < settings.num_cascades {
         radiance.read(next_ray)
     } else {
-        world.environment.read(facing)
+        world.environment_radiance(facing)
     };
 
     let merged_radiance = ray_fluence.over_color(next_radiance);
     let out_radiance = merged_radiance * weight;
 
-    let radiance_shared = Shared::<Radiance>::new(block_size().iter().product::<u32>() as usize);
-    let radiance_shared_2 =
-        Shared::<Radiance>::new(block_size().iter().product::<u32>() as usize / 2);
-
-    let block_probe_offset = block_size()[0] * (thread_id().y + block_size()[1] * thread_id().z);
-
-    radiance_shared.write(thread_id().x + block_probe_offset, out_radiance);
-
-    // Should be unnecessary since each warp includes 4 facings,
-    // but we don't have a sync_warp function and this can apparently break.
-    sync_block();
-
-    if thread_id().x % 2 == 0 {
-        let total_radiance = (0..2)
-            .map(|i| radiance_shared.read(thread_id().x + i + block_probe_offset))
-            .reduce(AddExpr::add)
-            .unwrap();
-        radiance_shared_2.write(thread_id().x / 2 + block_probe_offset / 2, total_radiance);
-    }
-
-    sync_block();
-
-    if facing % settings.branches() == 0 && thread_id().x % 2 == 0 {
-        let total_radiance = (0..settings.branches())
-            .map(|i| radiance_shared_2.read(thread_id().x / 2 + i + block_probe_offset / 2))
-            .reduce(AddExpr::add)
-            .unwrap();
-        let avg_radiance = total_radiance / settings.branches() as f32;
-        radiance.write(
-            RayLocation::from_comps_expr(RayLocationComps {
-                probe,
-                facing: facing / settings.branches(),
-                level,
-            }),
-            avg_radiance,
-        );
-    }
+    write_average(settings, radiance, out_radiance, probe, level, 2);
 }
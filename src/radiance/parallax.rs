@@ -0,0 +1,84 @@
+use super::*;
+
+/// Reads the radiance of the next level in any direction,
+/// linearly interpolating between the two nearest stored facings.
+#[tracked]
+fn read_direction(
+    world: &TraceWorld,
+    settings: &CascadeSettings,
+    radiance: &CascadeStorage<Radiance>,
+    probe: Expr<Vec2<u32>>,
+    dir: Expr<Vec2<f32>>,
+    level: Expr<u32>,
+) -> Expr<Radiance> {
+    let next_level = level + 1;
+    // The next level is stored prefiltered, so it has as many facings as this level.
+    let facings = settings.facing_count(level);
+    let t = (dir.y.atan2(dir.x) / TAU + 1.0) * facings.cast_f32() - 0.5;
+    let base = t.floor();
+    let fract = t - base;
+    let a = base.cast_u32() % facings;
+    let b = (a + 1) % facings;
+    let read = |facing: Expr<u32>| {
+        if next_level < settings.num_cascades {
+            radiance.read(RayLocation::from_comps_expr(RayLocationComps {
+                probe,
+                facing,
+                level: next_level,
+            }))
+        } else {
+            world.environment_radiance(facing)
+        }
+    };
+    read(a) * (1.0 - fract) + read(b) * fract
+}
+
+#[allow(unused)]
+#[tracked]
+pub fn merge(
+    world: &TraceWorld,
+    settings: &CascadeSettings,
+    radiance: &CascadeStorage<Radiance>,
+    noise: &Noise,
+    level: Expr<u32>,
+    frame: Expr<u32>,
+) {
+    let probe = dispatch_id().yz();
+    let facing = dispatch_id().x / 4;
+    let probe_offset = dispatch_id().x % 4;
+
+    let probe_pos = settings.probe_location(probe, level);
+
+    let ray_dir = settings.facing_direction(facing, level);
+
+    let interval = settings.interval(level);
+
+    let next_level = level + 1;
+    let samples = settings.bilinear_samples(probe, next_level);
+
+    let (next_probe, weight) = samples.sample(probe_offset);
+    let next_probe = keter::min(next_probe, settings.level_size_expr(next_level).probes - 1);
+    let next_probe_pos = settings.probe_location(next_probe, next_level);
+
+    // Instead of continuing in the same direction, each neighbour looks towards the point where the ray
+    // leaves the next interval, so that the four paths meet there rather than running in parallel.
+    let target = probe_pos + ray_dir * settings.interval_end(next_level);
+    let next_dir = (target - next_probe_pos).normalize();
+
+    let ray_start = probe_pos + ray_dir * interval.x;
+    let ray_end = next_probe_pos + next_dir * interval.y;
+
+    let ray_fluence = trace_radiance(
+        world,
+        ray_start,
+        (ray_end - ray_start).normalize(),
+        Vec2::expr(0.0, (ray_end - ray_start).length()),
+    );
+
+    let next_radiance = read_direction(world, settings, radiance, next_probe, next_dir, level);
+
+    let merged_radiance = ray_fluence.over_color(next_radiance);
+    let out_radiance = merged_radiance * weight;
+
+    write_average(settings, radiance, out_radiance, probe, level, 4);
+}
@@ -1,17 +1,14 @@
-use keter::lang::{
-    functions::{sync_block, thread_id},
-    types::shared::Shared,
-};
-
 use super::*;
 
 #[allow(unused)]
 #[tracked]
 pub fn merge(
     world: &TraceWorld,
-    settings: CascadeSettings,
+    settings: &CascadeSettings,
     radiance: &CascadeStorage<Radiance>,
+    noise: &Noise,
     level: Expr<u32>,
+    frame: Expr<u32>,
 ) {
     let probe = dispatch_id().yz();
     let facing = dispatch_id().x;
@@ -25,7 +22,8 @@ pub fn merge(
     let next_level = level + 1;
     let samples = settings.bilinear_samples(probe, next_level);
 
-    let rand = pcg3df(dispatch_id() + Vec3::expr(0, 0, level << 16)).xy();
+    // Changes every frame, so that it averages out when accumulated.
+    let rand = noise.sample2(probe, facing, level, frame);
     // let rand = Vec2::expr(facing % 2, (facing >> 1) % 2).cast_f32();
     // let rand = Vec2::expr((probe.x + probe.y) % 2, (probe.x + probe.y) % 2).cast_f32();
     let next_probe = samples.base_index + (rand < samples.fract).cast_u32();
@@ -48,35 +46,15 @@ pub fn merge(
     let next_radiance = if next_level < settings.num_cascades {
         radiance.read(next_ray)
     } else {
-        world.environment.read(facing)
+        world.environment_radiance(facing)
     };
 
-    let radiance_shared = Shared::<Radiance>::new(block_size().iter().product::<u32>() as usize);
-
-    let probe_offset = block_size()[0] * (thread_id().y + block_size()[1] * thread_id().z);
-
-    radiance_shared.write(
-        thread_id().x + probe_offset,
+    write_average(
+        settings,
+        radiance,
         ray_fluence.over_color(next_radiance),
+        probe,
+        level,
+        1,
     );
-
-    // Should be unnecessary since each warp includes 4 facings,
-    // but we don't have a sync_warp function and this can apparently break.
-    sync_block();
-
-    if facing % settings.branches() == 0 {
-        let total_radiance = (0..settings.branches())
-            .map(|i| radiance_shared.read(thread_id().x + i + probe_offset))
-            .reduce(AddExpr::add)
-            .unwrap();
-        let avg_radiance = total_radiance / settings.branches() as f32;
-        radiance.write(
-            RayLocation::from_comps_expr(RayLocationComps {
-                probe,
-                facing: facing / settings.branches(),
-                level,
-            }),
-            avg_radiance,
-        );
-    }
 }
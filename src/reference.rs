@@ -0,0 +1,63 @@
+//! Loading worlds and settings into the CPU reference, which lives in the `amida-reference` crate
+//! so that it builds and runs on machines without a compatible graphics card.
+
+pub use amida_reference::{render, Material, World};
+use radiance::MergeSettings;
+use world::{palette_materials, read_layers};
+
+use super::*;
+
+/// Loads the layers of a world file, like [`world::World::load`].
+pub fn load_world(path: impl AsRef<Path> + Copy, size: [u32; 2]) -> Result<World> {
+    let [display_opacity, display_diffuse, display_emissive, opacity, diffuse, emissive] =
+        <[_; 6]>::try_from(read_layers(path, size)?).unwrap();
+    Ok(World {
+        size,
+        emissive,
+        diffuse,
+        opacity,
+        display_emissive,
+        display_diffuse,
+        display_opacity,
+    })
+}
+
+/// Loads a palette image, with each color replaced by the layers of its material.
+pub fn load_palette_world(
+    path: impl AsRef<Path> + Copy,
+    palette: Palette,
+    materials: &[(String, LoadedMaterial)],
+    size: [u32; 2],
+) -> Result<World> {
+    let pixels = palette_materials(path, palette, materials, size)?;
+    let convert = |x: Vec3<f32>| FVec3::from(<[f32; 3]>::from(x));
+    let materials = materials
+        .iter()
+        .map(|(_, material)| Material {
+            emissive: convert(material.emissive),
+            diffuse: convert(material.diffuse),
+            opacity: convert(material.opacity),
+            display_emissive: convert(material.display_emissive),
+            display_diffuse: convert(material.display_diffuse),
+            display_opacity: convert(material.display_opacity),
+        })
+        .collect::<Vec<_>>();
+    Ok(World::from_materials(size, &pixels, &materials))
+}
+
+impl Settings {
+    /// The parts of the settings used by [`render`], with the merge variant of every level.
+    pub fn reference(&self) -> amida_reference::Settings {
+        let variants = |cascades: &CascadeSettings, merge: &MergeSettings| {
+            (0..cascades.num_cascades)
+                .map(|level| merge.variant(level))
+                .collect()
+        };
+        amida_reference::Settings {
+            cascades: self.cascades.reference(),
+            bounce_cascades: self.bounce_cascades.reference(),
+            display_merge: variants(&self.cascades, &self.display_merge),
+            bounce_merge: variants(&self.bounce_cascades, &self.bounce_merge),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use cli::RenderArgs;
+use image::{Rgb, RgbImage};
+use renderer::DisplayOptions;
+use utils::agx;
+
+use super::*;
+
+pub fn save_radiance(radiance: &[FVec3], size: [u32; 2], path: impl AsRef<Path>) -> Result<()> {
+    let data = radiance
+        .iter()
+        .copied()
+        .flat_map(<[f32; 3]>::from)
+        .collect::<Vec<_>>();
+    let path = path.as_ref().with_extension("tiff");
+    let file = File::create(&path).map_err(AmidaError::io(&path))?;
+    let mut file = TiffEncoder::new(file).map_err(AmidaError::tiff(&path))?;
+    file.write_image::<colortype::RGB32Float>(size[0], size[1], &data)
+        .map_err(AmidaError::tiff(&path))
+}
+
+/// Reads an image written by [`save_radiance`], returning the radiance and its size.
+pub fn load_radiance(path: impl AsRef<Path>) -> Result<(Vec<FVec3>, [u32; 2])> {
+    let path = path.as_ref().with_extension("tiff");
+    let file = File::open(&path).map_err(AmidaError::io(&path))?;
+    let mut file = TiffDecoder::new(file).map_err(AmidaError::tiff(&path))?;
+    let (width, height) = file.dimensions().map_err(AmidaError::tiff(&path))?;
+    let radiance = read_f32_rgb(&mut file, &path, None)?;
+    Ok((radiance, [width, height]))
+}
+
+pub fn save_tonemapped(radiance: &[FVec3], size: [u32; 2], path: impl AsRef<Path>) -> Result<()> {
+    let image = RgbImage::from_fn(size[0], size[1], |x, y| {
+        let color = agx(radiance[(x + y * size[0]) as usize]);
+        Rgb(<[f32; 3]>::from(color * 255.0).map(|c| c.round() as u8))
+    });
+    let path = path.as_ref().with_extension("png");
+    image.save(&path).map_err(AmidaError::image(&path))
+}
+
+/// Writes every level of the cascades as `<prefix>-level<N>.tiff`, with the probe grid of each stored facing
+/// as one tile, and the average over the facings of each probe as `<prefix>-level<N>-average.tiff`.
+/// Only the prefiltered facings are stored, so each facing is already the average of its branches.
+pub fn dump_cascades(cascades: &RadianceCascades, prefix: impl AsRef<Path>) -> Result<()> {
+    let prefix = prefix.as_ref();
+    let storage = cascades.radiance.settings();
+    for (level, radiance) in cascades.read_levels().into_iter().enumerate() {
+        let size = storage.level_size(level as u32);
+        let [width, height] = [size.probes.x, size.probes.y];
+        let columns = (size.facings as f32).sqrt().ceil() as u32;
+        let rows = size.facings.div_ceil(columns);
+
+        let tiled_size = [width * columns, height * rows];
+        let mut tiled = vec![FVec3::ZERO; (tiled_size[0] * tiled_size[1]) as usize];
+        let mut average = vec![FVec3::ZERO; (width * height) as usize];
+        for facing in 0..size.facings {
+            let origin = [facing % columns * width, facing / columns * height];
+            for y in 0..height {
+                for x in 0..width {
+                    let value = radiance[(x + width * (y + height * facing)) as usize];
+                    tiled[(origin[0] + x + (origin[1] + y) * tiled_size[0]) as usize] = value;
+                    average[(x + y * width) as usize] += value / size.facings as f32;
+                }
+            }
+        }
+
+        let mut name = prefix.file_name().unwrap_or_default().to_owned();
+        name.push(format!("-level{}", level));
+        let path = prefix.with_file_name(&name);
+        save_radiance(&tiled, tiled_size, &path)?;
+        name.push("-average");
+        save_radiance(&average, [width, height], prefix.with_file_name(name))?;
+    }
+    Ok(())
+}
+
+/// Renders the world without opening a window, writing the final radiance
+/// as `<output>.tiff` (linear) and `<output>.png` (tonemapped).
+pub fn run(args: RenderArgs) -> Result<()> {
+    let world_file_name = args.scene.world();
+    let env_file_name = args.scene.env();
+    let palette = args.scene.load_palette()?;
+
+    let settings = args.scene.load_settings()?;
+    let frames = args
+        .frames
+        .map_or(settings.render_frames, |frames| frames as usize);
+
+    let renderer = Renderer::new(&settings, load_materials(&settings.materials)?);
+    renderer.load_environment(&env_file_name)?;
+    renderer.load_world(&world_file_name, palette)?;
+
+    for _ in 0..frames.max(1) {
+        renderer
+            .commands(
+                settings.num_bounces,
+                &settings.display_merge,
+                DisplayOptions {
+                    run_final: true,
+                    display_level: settings.display_level,
+                    raw_radiance: settings.raw_radiance,
+                    overlay: None,
+                },
+            )
+            .execute();
+    }
+
+    let radiance = renderer.read_radiance();
+
+    let output = args.output.map(PathBuf::from).unwrap_or_else(|| {
+        let path = Path::new(&world_file_name);
+        let mut output_name = path.file_stem().unwrap().to_owned();
+        output_name.push("-render");
+        path.with_file_name(output_name)
+    });
+
+    save_radiance(&radiance, settings.world_size, &output)?;
+    save_tonemapped(&radiance, settings.world_size, &output)?;
+    println!("Rendered to {}", output.display());
+    if let Some(dir) = args.dump_cascades {
+        renderer.dump_cascades(&dir)?;
+        println!("Dumped the cascades to {}", dir.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,548 @@
+use std::cell::Cell;
+
+use super::*;
+use radiance::MergeSettings;
+use trace::trace_radiance;
+
+/// Which parts of the final (display) pass to run, and what to output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplayOptions {
+    /// Whether to run the display cascades at all. If not, the radiance is left as the last bounce.
+    pub run_final: bool,
+    /// The cascade level that is averaged to produce the radiance.
+    pub display_level: u32,
+    /// Output the incoming radiance without applying the display materials.
+    pub raw_radiance: bool,
+    /// Draw the probes and rays of the display cascades into the overlay texture.
+    pub overlay: Option<ProbeOverlay>,
+}
+
+/// The probes of a level, and the rays of the probe under the cursor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeOverlay {
+    pub level: u32,
+    pub cursor: Vec2<f32>,
+}
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        Self {
+            run_final: true,
+            display_level: 0,
+            raw_radiance: false,
+            overlay: None,
+        }
+    }
+}
+
+/// Owns the world and all of the GPU resources required to light it.
+pub struct Renderer {
+    grid_size: [u32; 2],
+    materials: Vec<(String, LoadedMaterial)>,
+    materials_buffer: Buffer<LoadedMaterial>,
+    world: World,
+    /// The environment filtered for the display cascades.
+    environment: Buffer<Radiance>,
+    /// The environment filtered for the bounce cascades.
+    bounce_environment: Buffer<Radiance>,
+    radiance: Tex2d<Radiance>,
+    difference: Tex2d<<BlockType as Block>::Storage>,
+    difference_blocks: Tex2d<bool>,
+    radiance_cascades: RadianceCascades,
+    bounce_radiance_cascades: RadianceCascades,
+    update_radiance_kernel: keter::runtime::Kernel<fn(u32)>,
+    finish_radiance_kernel: keter::runtime::Kernel<fn(u32, bool)>,
+    overlay: Tex2d<Radiance>,
+    overlay_kernel: keter::runtime::Kernel<fn(u32, Vec2<f32>)>,
+    accumulated: Tex2d<Radiance>,
+    accumulate_kernel: keter::runtime::Kernel<fn(f32)>,
+    /// The weight of each new frame in the moving average of the display radiance, if accumulating.
+    accumulation: Cell<Option<f32>>,
+    /// The number of frames in the moving average since it was last reset.
+    accumulated_frames: Cell<u32>,
+    frame: Cell<u32>,
+    bounce_merge: MergeSettings,
+    update_diff_kernel: keter::runtime::Kernel<fn(Tex2d<Opacity>)>,
+}
+
+impl Renderer {
+    pub fn new(settings: &Settings, materials: Vec<(String, LoadedMaterial)>) -> Self {
+        let grid_size = settings.world_size;
+
+        let materials_buffer = DEVICE.create_buffer_from_fn(materials.len(), |i| materials[i].1);
+
+        let cascades = &settings.cascades;
+        let bounce_cascades = &settings.bounce_cascades;
+
+        let world = World::new(grid_size[0], grid_size[1]);
+        // Black until an environment is set.
+        let environment = DEVICE
+            .create_buffer_from_fn(cascades.environment_facings() as usize, |_| {
+                Vec3::splat(0.0)
+            });
+        let bounce_environment = DEVICE
+            .create_buffer_from_fn(bounce_cascades.environment_facings() as usize, |_| {
+                Vec3::splat(0.0)
+            });
+
+        let radiance =
+            DEVICE.create_tex2d::<Radiance>(PixelStorage::Float4, grid_size[0], grid_size[1], 1);
+        let difference = DEVICE.create_tex2d::<<BlockType as Block>::Storage>(
+            BlockType::STORAGE_FORMAT,
+            grid_size[0].div_ceil(BlockType::SIZE),
+            grid_size[1].div_ceil(BlockType::SIZE),
+            1,
+        );
+        let difference_blocks = DEVICE.create_tex2d::<bool>(
+            PixelStorage::Byte1,
+            grid_size[0].div_ceil(BlockType::SIZE),
+            grid_size[1].div_ceil(BlockType::SIZE),
+            1,
+        );
+
+        let bounce_radiance_cascades = RadianceCascades::new(
+            "bounce",
+            bounce_cascades.clone(),
+            &TraceWorld {
+                size: grid_size,
+                radiance: radiance.view(0),
+                opacity: world.opacity.view(0),
+                environment: bounce_environment.view(..),
+                diff: difference.view(0),
+                diff_blocks: difference_blocks.view(0),
+            },
+            settings.bounce_tuning.clone(),
+        );
+        let display_world = TraceWorld {
+            size: grid_size,
+            radiance: radiance.view(0),
+            opacity: world.display_opacity.view(0),
+            environment: environment.view(..),
+            diff: difference.view(0),
+            diff_blocks: difference_blocks.view(0),
+        };
+        let radiance_cascades = RadianceCascades::new(
+            "display",
+            cascades.clone(),
+            &display_world,
+            settings.display_tuning.clone(),
+        );
+
+        let update_radiance_kernel = DEVICE.create_kernel::<fn(u32)>(&track!(|level| {
+            let storage_cascades = bounce_radiance_cascades.radiance.settings();
+
+            let total_radiance = Vec3::splat(0.0_f32).var();
+            for i in 0_u32.expr()..storage_cascades.facing_count(level) {
+                let ray = RayLocation::from_comps_expr(RayLocationComps {
+                    probe: storage_cascades.pixel_probe(dispatch_id().xy(), level),
+                    facing: i,
+                    level,
+                });
+                *total_radiance += bounce_radiance_cascades.radiance.read(ray);
+            }
+            let avg_radiance = total_radiance / storage_cascades.facing_count(level).cast_f32();
+
+            let emissive = world.emissive.read(dispatch_id().xy());
+            let diffuse = world.diffuse.read(dispatch_id().xy());
+
+            radiance.write(dispatch_id().xy(), avg_radiance * diffuse + emissive);
+        }));
+        let finish_radiance_kernel =
+            DEVICE.create_kernel::<fn(u32, bool)>(&track!(|level, raw| {
+                let storage_cascades = radiance_cascades.radiance.settings();
+
+                let total_radiance = Vec3::splat(0.0_f32).var();
+                for i in 0_u32.expr()..storage_cascades.facing_count(level) {
+                    let ray = RayLocation::from_comps_expr(RayLocationComps {
+                        probe: storage_cascades.pixel_probe(dispatch_id().xy(), level),
+                        facing: i,
+                        level,
+                    });
+                    *total_radiance += radiance_cascades.radiance.read(ray);
+                }
+                let avg_radiance = total_radiance / storage_cascades.facing_count(level).cast_f32();
+                radiance.write(
+                    dispatch_id().xy(),
+                    if raw {
+                        avg_radiance
+                    } else {
+                        let emissive = world.display_emissive.read(dispatch_id().xy());
+                        let diffuse = world.display_diffuse.read(dispatch_id().xy());
+                        avg_radiance * diffuse + emissive
+                    },
+                );
+            }));
+
+        let overlay =
+            DEVICE.create_tex2d::<Radiance>(PixelStorage::Float4, grid_size[0], grid_size[1], 1);
+        // Runs before the radiance is replaced by the final image, so the rays trace the same light as the cascades.
+        let overlay_kernel =
+            DEVICE.create_kernel::<fn(u32, Vec2<f32>)>(&track!(|level, cursor| {
+                let cascades = radiance_cascades.settings();
+                let pixel = dispatch_id().xy();
+                let pos = pixel.cast_f32() + 0.5;
+                let last_probe = cascades.probe_count(level) - 1;
+                let spacing = cascades.probe_spacing(level);
+                let color = Vec3::splat_expr(0.0_f32).var();
+
+                // With probes on every pixel the dots would cover the whole image, so only show sparser ones.
+                let nearest_probe = keter::min((pos / spacing).floor().cast_u32(), last_probe);
+                if spacing >= 2.0
+                    && (pos - cascades.probe_location(nearest_probe, level))
+                        .abs()
+                        .reduce_max()
+                        <= 0.5
+                {
+                    *color = Vec3::splat_expr(1.0_f32);
+                }
+
+                let cursor_probe = keter::min(
+                    (keter::max(cursor, Vec2::splat_expr(0.0_f32)) / spacing)
+                        .floor()
+                        .cast_u32(),
+                    last_probe,
+                );
+                let center = cascades.probe_location(cursor_probe, level);
+                let offset = pos - center;
+                let interval = cascades.interval(level);
+                let distance = offset.length();
+                if distance >= interval.x && distance <= interval.y {
+                    // Only the facing in the direction of the pixel can pass through it.
+                    let facings = cascades.facing_count(level);
+                    let turns = offset.y.atan2(offset.x) / TAU;
+                    let facing = keter::min(
+                        ((turns - turns.floor()) * facings.cast_f32()).cast_u32(),
+                        facings - 1,
+                    );
+                    let ray_dir = cascades.facing_direction(facing, level);
+                    if (offset - ray_dir * offset.dot(ray_dir)).length() <= 0.5 {
+                        let fluence = trace_radiance(
+                            &display_world,
+                            center + ray_dir * interval.x,
+                            ray_dir,
+                            Vec2::expr(0.0, interval.y - interval.x),
+                        );
+                        // Dim grey for the light let through, so rays that pick up nothing are still visible.
+                        *color = fluence.radiance + fluence.transmittance * 0.1;
+                    }
+                }
+                overlay.write(pixel, **color);
+            }));
+
+        let accumulated =
+            DEVICE.create_tex2d::<Radiance>(PixelStorage::Float4, grid_size[0], grid_size[1], 1);
+        let accumulate_kernel = DEVICE.create_kernel::<fn(f32)>(&track!(|weight| {
+            let pixel = dispatch_id().xy();
+            let value = accumulated.read(pixel) * (1.0 - weight) + radiance.read(pixel) * weight;
+            accumulated.write(pixel, value);
+            radiance.write(pixel, value);
+        }));
+
+        let update_diff_kernel = DEVICE.create_kernel::<fn(Tex2d<Opacity>)>(&track!(|opacity| {
+            let block = BlockType::empty().var();
+            for dx in 0..BlockType::SIZE {
+                for dy in 0..BlockType::SIZE {
+                    let pos = dispatch_id().xy() * BlockType::SIZE + Vec2::expr(dx, dy);
+                    let diff = false.var();
+                    // The last blocks may extend past the edge of non-divisible worlds.
+                    if (pos < Vec2::from(grid_size).expr()).all() {
+                        let this_radiance = radiance.read(pos);
+                        let this_opacity = opacity.read(pos);
+                        for i in 0_u32..4_u32 {
+                            let offset = [
+                                Vec2::new(1, 0),
+                                Vec2::new(-1, 0),
+                                Vec2::new(0, 1),
+                                Vec2::new(0, -1),
+                            ]
+                            .expr()[i];
+                            let neighbor = pos.cast_i32() + offset;
+                            if (neighbor >= 0).all()
+                                && (neighbor < Vec2::from(grid_size).expr().cast_i32()).all()
+                            {
+                                let neighbor_radiance = radiance.read(neighbor.cast_u32());
+                                let neighbor_opacity = opacity.read(neighbor.cast_u32());
+                                if (neighbor_radiance != this_radiance).any()
+                                    || (neighbor_opacity != this_opacity).any()
+                                {
+                                    *diff = true;
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    if diff {
+                        BlockType::set(block, Vec2::expr(dx, dy));
+                    }
+                }
+            }
+            difference_blocks.write(dispatch_id().xy(), !BlockType::is_empty(**block));
+            BlockType::write(&difference.view(0), dispatch_id().xy(), **block);
+        }));
+
+        Self {
+            grid_size,
+            materials,
+            materials_buffer,
+            world,
+            environment,
+            bounce_environment,
+            radiance,
+            difference,
+            difference_blocks,
+            radiance_cascades,
+            bounce_radiance_cascades,
+            update_radiance_kernel,
+            finish_radiance_kernel,
+            overlay,
+            overlay_kernel,
+            accumulated,
+            accumulate_kernel,
+            accumulation: Cell::new(settings.accumulation),
+            accumulated_frames: Cell::new(0),
+            frame: Cell::new(0),
+            bounce_merge: settings.bounce_merge.clone(),
+            update_diff_kernel,
+        }
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.grid_size
+    }
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+    pub fn radiance(&self) -> &Tex2d<Radiance> {
+        &self.radiance
+    }
+    pub fn materials(&self) -> &[(String, LoadedMaterial)] {
+        &self.materials
+    }
+    pub fn materials_buffer(&self) -> &Buffer<LoadedMaterial> {
+        &self.materials_buffer
+    }
+    pub fn material_index(&self, name: &str) -> Option<u32> {
+        self.materials
+            .iter()
+            .position(|(n, _)| n == name)
+            .map(|i| i as u32)
+    }
+    pub(crate) fn difference(&self) -> &Tex2d<<BlockType as Block>::Storage> {
+        &self.difference
+    }
+    pub(crate) fn difference_blocks(&self) -> &Tex2d<bool> {
+        &self.difference_blocks
+    }
+    /// The probe overlay, drawn during [`Renderer::commands`] if enabled in the [`DisplayOptions`].
+    pub(crate) fn overlay(&self) -> &Tex2d<Radiance> {
+        &self.overlay
+    }
+    pub fn cascades(&self) -> &RadianceCascades {
+        &self.radiance_cascades
+    }
+    pub fn bounce_cascades(&self) -> &RadianceCascades {
+        &self.bounce_radiance_cascades
+    }
+    pub fn num_cascades(&self) -> u32 {
+        self.radiance_cascades.settings().num_cascades
+    }
+
+    pub fn accumulation(&self) -> Option<f32> {
+        self.accumulation.get()
+    }
+    /// Sets the weight of each new frame in the moving average of the display radiance,
+    /// or stops averaging if `None`.
+    pub fn set_accumulation(&self, accumulation: Option<f32>) {
+        self.accumulation.set(accumulation);
+        self.reset_accumulation();
+    }
+    /// Restarts the moving average from the next frame, which has to be done whenever the lighting changes.
+    /// This is done automatically when loading a world or environment.
+    pub fn reset_accumulation(&self) {
+        self.accumulated_frames.set(0);
+    }
+
+    /// Sets the environment from an angular map of any resolution, such as one loaded by [`load_env`].
+    /// It is box-filtered over the facings of the top level of each cascade, and an empty map is black.
+    pub fn set_environment(&self, data: &[FVec3]) {
+        self.reset_accumulation();
+        for (cascades, environment) in [
+            (&self.radiance_cascades, &self.environment),
+            (&self.bounce_radiance_cascades, &self.bounce_environment),
+        ] {
+            let facings = cascades.settings().environment_facings();
+            let staging = amida_reference::filter_env(data, facings)
+                .into_iter()
+                .map(Vec3::from)
+                .collect::<Vec<_>>();
+            environment.copy_from(&staging);
+        }
+    }
+    pub fn load_environment(&self, path: &str) -> Result<()> {
+        if std::fs::exists(path).unwrap_or(false) {
+            self.set_environment(&load_env(path)?);
+        }
+        Ok(())
+    }
+
+    /// Loads the world, returning the path that the world should be saved to.
+    pub fn load_world(&self, path: &str, palette: Option<Palette>) -> Result<String> {
+        self.reset_accumulation();
+        if let Some(palette) = palette {
+            self.world.load_palette(path, palette, &self.materials)?;
+            Ok(path.to_string() + ".tiff")
+        } else {
+            if std::fs::exists(path).unwrap_or(false) {
+                self.world.load(path)?;
+            } else {
+                self.world.load_default();
+            }
+            Ok(path.to_string())
+        }
+    }
+
+    /// Runs the bounces and the display pass, blocking until finished.
+    pub fn step(&self, num_bounces: usize, merge: &MergeSettings) {
+        self.commands(num_bounces, merge, DisplayOptions::default())
+            .execute();
+    }
+
+    /// The commands for a single frame, for use when the caller wants to schedule or time them.
+    /// Each call advances the frame, which seeds the stochastic merges and the moving average.
+    pub fn commands(
+        &self,
+        num_bounces: usize,
+        merge: &MergeSettings,
+        display: DisplayOptions,
+    ) -> impl AsNodes + '_ {
+        let DisplayOptions {
+            run_final,
+            display_level,
+            raw_radiance,
+            overlay,
+        } = display;
+        let grid_dispatch = [self.grid_size[0], self.grid_size[1], 1];
+        let frame = self.frame.get();
+        self.frame.set(frame.wrapping_add(1));
+        // Starts as the mean of all frames, until their weight drops below the moving average's.
+        let accumulate_weight =
+            run_final
+                .then(|| self.accumulation.get())
+                .flatten()
+                .map(|weight| {
+                    let frames = self.accumulated_frames.get();
+                    self.accumulated_frames.set(frames.saturating_add(1));
+                    weight.max(1.0 / (frames + 1) as f32)
+                });
+        (
+            self.bounce_commands(num_bounces, frame),
+            run_final.then(|| {
+                (
+                    self.display_diff_command(),
+                    self.radiance_cascades.update(merge, frame),
+                    overlay.map(|overlay| {
+                        self.overlay_kernel
+                            .dispatch_async(grid_dispatch, &overlay.level, &overlay.cursor)
+                            .debug("Overlay")
+                    }),
+                    self.finish_radiance_kernel
+                        .dispatch_async(grid_dispatch, &display_level, &raw_radiance)
+                        .debug("Finish radiance"),
+                    accumulate_weight.map(|weight| {
+                        self.accumulate_kernel
+                            .dispatch_async(grid_dispatch, &weight)
+                            .debug("Accumulate")
+                    }),
+                )
+                    .chain()
+            }),
+        )
+            .chain()
+    }
+
+    /// Resets the radiance to the emissive layer and runs the bounces.
+    pub(crate) fn bounce_commands(&self, num_bounces: usize, frame: u32) -> impl AsNodes + '_ {
+        let grid_dispatch = [self.grid_size[0], self.grid_size[1], 1];
+        (
+            self.world
+                .emissive
+                .view(0)
+                .copy_to_texture_async(&self.radiance.view(0)),
+            (0..num_bounces)
+                .map(|_i| {
+                    (
+                        self.update_diff_kernel
+                            .dispatch_async(
+                                [
+                                    self.grid_size[0].div_ceil(BlockType::SIZE),
+                                    self.grid_size[1].div_ceil(BlockType::SIZE),
+                                    1,
+                                ],
+                                &self.world.opacity,
+                            )
+                            .debug("Update bounce diff"),
+                        self.bounce_radiance_cascades
+                            .update(&self.bounce_merge, frame),
+                        self.update_radiance_kernel
+                            .dispatch_async(grid_dispatch, &0)
+                            .debug("Update radiance"),
+                    )
+                        .chain()
+                })
+                .collect::<Vec<_>>(),
+        )
+            .chain()
+    }
+
+    /// Updates the difference map for tracing through the display opacity.
+    pub(crate) fn display_diff_command(&self) -> impl AsNodes + '_ {
+        self.update_diff_kernel
+            .dispatch_async(
+                [
+                    self.grid_size[0].div_ceil(BlockType::SIZE),
+                    self.grid_size[1].div_ceil(BlockType::SIZE),
+                    1,
+                ],
+                &self.world.display_opacity,
+            )
+            .debug("Update display diff")
+    }
+
+    /// Writes every level of both cascades into the directory, as `display-level<N>.tiff`
+    /// and `bounce-level<N>.tiff` with their averages; see [`render::dump_cascades`].
+    pub fn dump_cascades(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir).map_err(AmidaError::io(dir))?;
+        render::dump_cascades(&self.radiance_cascades, dir.join("display"))?;
+        render::dump_cascades(&self.bounce_radiance_cascades, dir.join("bounce"))
+    }
+
+    /// Copies the current radiance back to the host, in row-major order.
+    pub fn read_radiance(&self) -> Vec<FVec3> {
+        read_texture(&self.radiance, self.grid_size)
+    }
+}
+
+/// Copies a texture back to the host, in row-major order.
+pub fn read_texture(texture: &Tex2d<Radiance>, size: [u32; 2]) -> Vec<FVec3> {
+    let [width, height] = size;
+    let staging_buffer = DEVICE.create_buffer::<f32>(3 * (width * height) as usize);
+    let staging_kernel = DEVICE.create_kernel::<fn()>(&track!(|| {
+        let index = 3 * (dispatch_id().x + dispatch_id().y * width);
+        let value = texture.read(dispatch_id().xy());
+        staging_buffer.write(index, value.x);
+        staging_buffer.write(index + 1, value.y);
+        staging_buffer.write(index + 2, value.z);
+    }));
+    let mut staging_host = vec![0.0_f32; 3 * (width * height) as usize];
+    (
+        staging_kernel.dispatch_async([width, height, 1]),
+        staging_buffer.copy_to_async(&mut staging_host),
+    )
+        .chain()
+        .execute();
+    staging_host
+        .into_iter()
+        .array_chunks::<3>()
+        .map(FVec3::from)
+        .collect()
+}
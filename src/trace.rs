@@ -113,6 +113,8 @@ pub struct TraceWorld<B: Block = BlockType> {
     pub size: [u32; 2],
     pub radiance: Tex2dView<Radiance>,
     pub opacity: Tex2dView<Opacity>,
+    /// The environment over each facing of the top level of the cascade, filtered on the host with
+    /// [`filter_env`](amida_reference::filter_env).
     pub environment: BufferView<Radiance>,
     pub diff: Tex2dView<B::Storage>,
     pub diff_blocks: Tex2dView<bool>,
@@ -124,9 +126,13 @@ impl<B: Block> TraceWorld<B> {
     pub fn height(&self) -> u32 {
         self.size[1]
     }
+    /// The environment over facing `facing` of the top level, which is already box-filtered over the facing.
+    pub fn environment_radiance(&self, facing: Expr<u32>) -> Expr<Radiance> {
+        self.environment.read(facing)
+    }
 }
 
-const TRANSMITTANCE_CUTOFF: f32 = 0.001;
+pub(crate) use amida_reference::TRANSMITTANCE_CUTOFF;
 
 fn intersect_intervals(a: Expr<Interval>, b: Expr<Interval>) -> Expr<Interval> {
     Vec2::expr(keter::max(a.x, b.x), keter::min(a.y, b.y))
@@ -0,0 +1,162 @@
+use cli::TruthArgs;
+use render::{save_radiance, save_tonemapped};
+use renderer::read_texture;
+use trace::trace_radiance;
+use utils::pcg3df;
+
+use super::*;
+
+/// Brute-force lighting of the display pass, for comparing against the cascades.
+///
+/// Each frame marches `rays` stratified rays from every pixel all the way to the environment,
+/// compositing with the same `Fluence::over` rules as the cascades,
+/// and accumulates the result into a running mean.
+pub struct GroundTruth {
+    grid_size: [u32; 2],
+    rays: u32,
+    // Only read by the kernel.
+    #[allow(dead_code)]
+    environment: Buffer<Radiance>,
+    accumulated: Tex2d<Radiance>,
+    kernel: keter::runtime::Kernel<fn(u32, u32)>,
+}
+
+impl GroundTruth {
+    /// Uses the bounce lighting and display layers of the renderer, and the full resolution environment.
+    /// An empty environment is treated as black.
+    pub fn new(renderer: &Renderer, environment: &[FVec3], rays: u32) -> Self {
+        let grid_size = renderer.size();
+        let world = renderer.world();
+
+        let environment = if environment.is_empty() {
+            DEVICE.create_buffer_from_slice(&[Vec3::splat(0.0)])
+        } else {
+            DEVICE.create_buffer_from_fn(environment.len(), |i| {
+                Vec3::from(<[f32; 3]>::from(environment[i]))
+            })
+        };
+        let accumulated =
+            DEVICE.create_tex2d::<Radiance>(PixelStorage::Float4, grid_size[0], grid_size[1], 1);
+
+        let trace_world = TraceWorld {
+            size: grid_size,
+            radiance: renderer.radiance().view(0),
+            opacity: world.display_opacity.view(0),
+            environment: environment.view(..),
+            diff: renderer.difference().view(0),
+            diff_blocks: renderer.difference_blocks().view(0),
+        };
+        let env_facings = environment.len() as u32;
+        let max_distance = (grid_size[0] as f32).hypot(grid_size[1] as f32);
+
+        let kernel = DEVICE.create_kernel::<fn(u32, u32)>(&track!(|frame, rays| {
+            let pixel = dispatch_id().xy();
+            let pos = pixel.cast_f32() + 0.5;
+            let offset = pcg3df(Vec3::expr(pixel.x, pixel.y, frame)).x;
+
+            let total_radiance = Vec3::splat(0.0_f32).var();
+            for i in 0_u32.expr()..rays {
+                let t = (i.cast_f32() + offset) / rays.cast_f32();
+                let angle = t * TAU;
+                let ray_dir = Vec2::expr(angle.cos(), angle.sin());
+                let fluence =
+                    trace_radiance(&trace_world, pos, ray_dir, Vec2::expr(0.0, max_distance));
+                let env_facing = keter::min((t * env_facings as f32).cast_u32(), env_facings - 1);
+                *total_radiance += fluence.over_color(trace_world.environment.read(env_facing));
+            }
+            let avg_radiance = total_radiance / rays.cast_f32();
+
+            let emissive = world.display_emissive.read(pixel);
+            let diffuse = world.display_diffuse.read(pixel);
+            let value = avg_radiance * diffuse + emissive;
+
+            let old = accumulated.read(pixel);
+            accumulated.write(
+                pixel,
+                if frame == 0 {
+                    value
+                } else {
+                    old + (value - old) / (frame + 1).cast_f32()
+                },
+            );
+        }));
+
+        Self {
+            grid_size,
+            rays,
+            environment,
+            accumulated,
+            kernel,
+        }
+    }
+
+    /// Runs the bounces of the renderer, then accumulates another frame of rays.
+    /// Frame `0` resets the accumulation.
+    pub fn commands<'a>(
+        &'a self,
+        renderer: &'a Renderer,
+        num_bounces: usize,
+        frame: u32,
+    ) -> impl AsNodes + 'a {
+        (
+            renderer.bounce_commands(num_bounces, frame),
+            renderer.display_diff_command(),
+            self.kernel
+                .dispatch_async(
+                    [self.grid_size[0], self.grid_size[1], 1],
+                    &frame,
+                    &self.rays,
+                )
+                .debug("Ground truth"),
+        )
+            .chain()
+    }
+
+    /// Copies the accumulated radiance back to the host, in row-major order.
+    pub fn read(&self) -> Vec<FVec3> {
+        read_texture(&self.accumulated, self.grid_size)
+    }
+}
+
+/// Renders the converged ground truth, writing `<output>.tiff` (linear) and `<output>.png` (tonemapped).
+pub fn run(args: TruthArgs) -> Result<()> {
+    let world_file_name = args.scene.world();
+    let env_file_name = args.scene.env();
+    let palette = args.scene.load_palette()?;
+    let settings = args.scene.load_settings()?;
+
+    let renderer = Renderer::new(&settings, load_materials(&settings.materials)?);
+    let environment = if Path::new(&env_file_name).is_file() {
+        load_env(&env_file_name)?
+    } else {
+        Vec::new()
+    };
+    if !environment.is_empty() {
+        renderer.set_environment(&environment);
+    }
+    renderer.load_world(&world_file_name, palette)?;
+
+    let truth = GroundTruth::new(&renderer, &environment, args.rays);
+    for frame in 0..args.frames {
+        truth
+            .commands(&renderer, settings.num_bounces, frame)
+            .execute();
+    }
+    let radiance = truth.read();
+
+    let output = args.output.map(PathBuf::from).unwrap_or_else(|| {
+        let path = Path::new(&world_file_name);
+        let mut output_name = path.file_stem().unwrap().to_owned();
+        output_name.push("-truth");
+        path.with_file_name(output_name)
+    });
+
+    save_radiance(&radiance, settings.world_size, &output)?;
+    save_tonemapped(&radiance, settings.world_size, &output)?;
+    println!(
+        "Rendered {} rays per pixel to {}",
+        args.frames * args.rays,
+        output.display()
+    );
+    Ok(())
+}
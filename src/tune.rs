@@ -0,0 +1,215 @@
+use cli::TuneArgs;
+use radiance::{MergeSettings, MergeVariant, RadianceCascades, TuningSettings};
+use renderer::DisplayOptions;
+
+use super::*;
+
+/// Block sizes with `threads` threads in total, with at least 4 facings and at most 8 probes along each axis.
+fn candidate_block_sizes(threads: &[u32]) -> Vec<[u32; 3]> {
+    let mut candidates = Vec::new();
+    for &threads in threads {
+        for x in (2..=threads.ilog2()).map(|i| 1 << i) {
+            for y in (0..=3).map(|i| 1 << i) {
+                for z in (0..=3).map(|i| 1 << i) {
+                    if x * y * z == threads {
+                        candidates.push([x, y, z]);
+                    }
+                }
+            }
+        }
+    }
+    candidates
+}
+
+/// The mean time of each level of each merge, split into the bounce and display cascades.
+struct LevelTimes {
+    bounce: Vec<f32>,
+    display: Vec<f32>,
+}
+
+fn measure(
+    renderer: &Renderer,
+    num_bounces: usize,
+    merge: &MergeSettings,
+    warmup: u32,
+    frames: u32,
+) -> LevelTimes {
+    let mut times = LevelTimes {
+        bounce: vec![0.0; renderer.bounce_cascades().settings().num_cascades as usize],
+        display: vec![0.0; renderer.cascades().settings().num_cascades as usize],
+    };
+    for _ in 0..warmup {
+        renderer
+            .commands(num_bounces, merge, DisplayOptions::default())
+            .execute();
+    }
+    for _ in 0..frames {
+        let timings = renderer
+            .commands(num_bounces, merge, DisplayOptions::default())
+            .execute_timed();
+        for (name, value) in timings.iter() {
+            if let Some(level) = name.strip_prefix("bounce merge level ") {
+                let level = level.parse::<usize>().unwrap();
+                times.bounce[level] += *value / (frames as usize * num_bounces) as f32;
+            } else if let Some(level) = name.strip_prefix("display merge level ") {
+                let level = level.parse::<usize>().unwrap();
+                times.display[level] += *value / frames as f32;
+            }
+        }
+    }
+    times
+}
+
+/// Groups the levels by their fastest block size.
+/// Levels without any candidates keep their block size from `current`.
+fn fastest(name: &str, times: &[Vec<([u32; 3], f32)>], current: &TuningSettings) -> TuningSettings {
+    let mut block_sizes = HashMap::<[u32; 3], Vec<u32>>::new();
+    for (level, candidates) in times.iter().enumerate() {
+        let level = level as u32;
+        let block_size = match candidates.iter().min_by(|a, b| a.1.total_cmp(&b.1)) {
+            Some((block_size, _)) => *block_size,
+            None => {
+                let Some((_, block_size)) = current
+                    .block_sizes
+                    .iter()
+                    .find(|(levels, _)| levels.contains(&level))
+                else {
+                    continue;
+                };
+                println!(
+                    "{} level {}: no candidate block sizes, keeping {:?}",
+                    name, level, block_size
+                );
+                *block_size
+            }
+        };
+        block_sizes.entry(block_size).or_default().push(level);
+    }
+    TuningSettings {
+        block_sizes: block_sizes
+            .into_iter()
+            .map(|(block_size, levels)| (levels, block_size))
+            .collect(),
+    }
+}
+
+fn print_times(name: &str, times: &[Vec<([u32; 3], f32)>]) {
+    println!("{}:", name);
+    for (level, candidates) in times.iter().enumerate() {
+        let (Some((best, best_time)), Some((worst, worst_time))) = (
+            candidates.iter().min_by(|a, b| a.1.total_cmp(&b.1)),
+            candidates.iter().max_by(|a, b| a.1.total_cmp(&b.1)),
+        ) else {
+            println!("  Level {}: no candidate block sizes", level);
+            continue;
+        };
+        println!(
+            "  Level {}: fastest {:?} at {:.4}ms, slowest {:?} at {:.4}ms",
+            level, best, best_time, worst, worst_time
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct Tuning {
+    bounce_tuning: TuningSettings,
+    display_tuning: TuningSettings,
+}
+
+/// Times every candidate block size on each level of each merge variant,
+/// and writes the fastest assignment as RON that can be copied into the settings.
+pub fn run(args: TuneArgs) -> Result<()> {
+    let settings = args.scene.load_settings()?;
+    let materials = load_materials(&settings.materials)?;
+    let palette = args.scene.load_palette()?;
+    // The bounce cascades have to run to be timed.
+    let num_bounces = settings.num_bounces.max(1);
+
+    let candidates = candidate_block_sizes(&args.threads);
+    let mut bounce_times = vec![vec![]; settings.bounce_cascades.num_cascades as usize];
+    let mut display_times =
+        vec![vec![vec![]; settings.cascades.num_cascades as usize]; MergeVariant::ALL.len()];
+
+    for (i, &candidate) in candidates.iter().enumerate() {
+        println!(
+            "Timing block size {:?} ({}/{})",
+            candidate,
+            i + 1,
+            candidates.len()
+        );
+        let mut tuned = settings.clone();
+        tuned.bounce_tuning =
+            TuningSettings::uniform(candidate, settings.bounce_cascades.num_cascades);
+        tuned.display_tuning = TuningSettings::uniform(candidate, settings.cascades.num_cascades);
+
+        let renderer = Renderer::new(&tuned, materials.clone());
+        renderer.load_environment(&args.scene.env())?;
+        renderer.load_world(&args.scene.world(), palette.clone())?;
+
+        for (variant, variant_times) in MergeVariant::ALL.into_iter().zip(&mut display_times) {
+            // Block sizes below the minimum are rounded up, so they would duplicate another candidate.
+            let min_block_size = RadianceCascades::min_block_size(variant);
+            if (0..3).any(|i| candidate[i] < min_block_size[i]) {
+                continue;
+            }
+            let times = measure(
+                &renderer,
+                num_bounces,
+                &MergeSettings::uniform(variant),
+                args.warmup,
+                args.frames,
+            );
+            for (level, time) in times.display.into_iter().enumerate() {
+                variant_times[level].push((candidate, time));
+            }
+            // The bounce cascades use their own variants from the settings, so only need timing once.
+            if variant == MergeVariant::ALL[0] {
+                for (level, time) in times.bounce.into_iter().enumerate() {
+                    bounce_times[level].push((candidate, time));
+                }
+            }
+        }
+    }
+
+    print_times("Bounce cascades", &bounce_times);
+    for (variant, times) in MergeVariant::ALL.into_iter().zip(&display_times) {
+        if times.iter().all(Vec::is_empty) {
+            println!(
+                "Display cascades, {} merge: skipped, no candidate block size is at least {:?}",
+                variant,
+                RadianceCascades::min_block_size(variant)
+            );
+            continue;
+        }
+        print_times(&format!("Display cascades, {} merge", variant), times);
+    }
+
+    // Each level is tuned for the variant it uses in the settings.
+    let merged_display_times = (0..settings.cascades.num_cascades)
+        .map(|level| {
+            display_times[settings.display_merge.variant(level) as usize][level as usize].clone()
+        })
+        .collect::<Vec<_>>();
+    let tuning = Tuning {
+        bounce_tuning: fastest("Bounce cascades", &bounce_times, &settings.bounce_tuning),
+        display_tuning: fastest(
+            "Display cascades",
+            &merged_display_times,
+            &settings.display_tuning,
+        ),
+    };
+    let output = args.output.map(PathBuf::from).unwrap_or_else(|| {
+        let path = Path::new(&args.scene.settings()).to_path_buf();
+        let mut output_name = path.file_stem().unwrap().to_owned();
+        output_name.push("-tuning.ron");
+        path.with_file_name(output_name)
+    });
+    let data = ron::ser::to_string_pretty(&tuning, ron::ser::PrettyConfig::default())
+        .expect("Tuning settings should always serialize");
+    std::fs::write(&output, data).map_err(AmidaError::io(&output))?;
+    println!(
+        "Wrote the fastest block sizes for the merge variants in the settings to {}",
+        output.display()
+    );
+    Ok(())
+}
@@ -26,12 +26,6 @@ pub fn pcg(v: Expr<u32>) -> Expr<u32> {
     (word >> 22u32) ^ word
 }
 
-pub fn pcg_host(v: u32) -> u32 {
-    let state = v.wrapping_mul(747796405u32).wrapping_add(2891336453u32);
-    let word = ((state >> (state >> 28u32).wrapping_add(4u32)) ^ state).wrapping_mul(277803737u32);
-    (word >> 22u32) ^ word
-}
-
 /*
 Taken from: https://www.shadertoy.com/view/tlcSzs
 
@@ -50,3 +44,43 @@ vec3 SRGBToLinear ( vec3 col )
 pub fn pcg3df(v: Expr<Vec3<u32>>) -> Expr<Vec3<f32>> {
     pcg3d(v).cast_f32() / u32::MAX as f32
 }
+
+// https://iolite-engine.com/blog_posts/minimal_agx_implementation
+pub fn agx(color: FVec3) -> FVec3 {
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let inset = glam::Mat3::from_cols_array(&[
+        0.842479062253094,
+        0.0423282422610123,
+        0.0423756549057051,
+        0.0784335999999992,
+        0.878468636469772,
+        0.0784336,
+        0.0792237451477643,
+        0.0791661274605434,
+        0.879142973793104,
+    ]);
+    let outset = glam::Mat3::from_cols_array(&[
+        1.19687900512017,
+        -0.0528968517574562,
+        -0.0529716355144438,
+        -0.0980208811401368,
+        1.15190312990417,
+        -0.0980434501171241,
+        -0.0990297440797205,
+        -0.0989611768448433,
+        1.15107367264116,
+    ]);
+
+    let x = (inset * color.max(FVec3::splat(f32::MIN_POSITIVE)))
+        .map(f32::log2)
+        .clamp(FVec3::splat(MIN_EV), FVec3::splat(MAX_EV));
+    let x = (x - MIN_EV) / (MAX_EV - MIN_EV);
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let x =
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232;
+    (outset * x).clamp(FVec3::ZERO, FVec3::ONE)
+}
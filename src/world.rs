@@ -1,5 +1,5 @@
+use amida_reference::hash::pcg;
 use image::ImageReader;
-use utils::pcg_host;
 
 use super::*;
 
@@ -15,6 +15,16 @@ pub struct World {
 
 const PAGENAME: Tag = Tag::Unknown(285);
 
+/// The names of the layers in a world file. Generally viewed in reverse.
+pub const LAYERS: [&str; 6] = [
+    "display_opacity",
+    "display_diffuse",
+    "display_emissive",
+    "opacity",
+    "diffuse",
+    "emissive",
+];
+
 impl World {
     pub fn new(width: u32, height: u32) -> Self {
         Self {
@@ -41,22 +51,13 @@ impl World {
             }))
             .dispatch([self.width(), self.height(), 1]);
     }
-    pub fn load(&self, path: impl AsRef<Path> + Copy) {
-        let file = File::open(path.as_ref().with_extension("tiff")).unwrap();
-        let mut file = TiffDecoder::new(file).unwrap();
-
-        let base_colortype = file.colortype().unwrap();
-        let is_rgba = match base_colortype {
-            ColorType::RGB(32) => false,
-            ColorType::RGBA(32) => true,
-            _ => panic!("Unsupported color type"),
-        };
-        let stride = if is_rgba { 4 } else { 3 };
+    pub fn load(&self, path: impl AsRef<Path> + Copy) -> Result<()> {
+        let layers = read_layers(path, self.size)?;
 
         let staging_buffer =
-            DEVICE.create_buffer::<f32>(stride * (self.width() * self.height()) as usize);
+            DEVICE.create_buffer::<f32>(3 * (self.width() * self.height()) as usize);
         let staging_kernel = DEVICE.create_kernel::<fn(Tex2d<Radiance>)>(&track!(|texture| {
-            let index = stride as u32 * (dispatch_id().x + dispatch_id().y * self.width());
+            let index = 3 * (dispatch_id().x + dispatch_id().y * self.width());
             let value = Vec3::expr(
                 staging_buffer.read(index),
                 staging_buffer.read(index + 1),
@@ -65,39 +66,33 @@ impl World {
             texture.write(dispatch_id().xy(), value);
         }));
 
-        let mut load = |name: &str, texture: &Tex2d<Radiance>| {
-            assert_eq!(
-                file.get_tag_ascii_string(PAGENAME)
-                    .as_deref()
-                    .unwrap_or(name),
-                name,
-                "Layer names do not match"
-            );
-            assert_eq!(file.colortype().unwrap(), base_colortype);
-            assert_eq!(file.dimensions().unwrap(), (self.width(), self.height()));
-            let image = file.read_image().unwrap();
-            let DecodingResult::F32(image) = image else {
-                unreachable!()
-            };
+        for (layer, texture) in layers.iter().zip(self.layers()) {
+            let data = layer
+                .iter()
+                .copied()
+                .flat_map(<[f32; 3]>::from)
+                .collect::<Vec<_>>();
             (
-                staging_buffer.copy_from_async(&image),
+                staging_buffer.copy_from_async(&data),
                 staging_kernel.dispatch_async([self.width(), self.height(), 1], texture),
             )
                 .chain()
                 .execute();
-            if file.more_images() {
-                file.next_image().unwrap();
-            }
-        };
-        // Generally viewed in reverse.
-        load("display_opacity", &self.display_opacity);
-        load("display_diffuse", &self.display_diffuse);
-        load("display_emissive", &self.display_emissive);
-        load("opacity", &self.opacity);
-        load("diffuse", &self.diffuse);
-        load("emissive", &self.emissive);
+        }
+        Ok(())
+    }
+    /// The textures of each layer, in the order of [`LAYERS`].
+    pub fn layers(&self) -> [&Tex2d<Radiance>; 6] {
+        [
+            &self.display_opacity,
+            &self.display_diffuse,
+            &self.display_emissive,
+            &self.opacity,
+            &self.diffuse,
+            &self.emissive,
+        ]
     }
-    pub fn save(&self, path: impl AsRef<Path> + Copy) {
+    pub fn save(&self, path: impl AsRef<Path> + Copy) -> Result<()> {
         let staging_buffer =
             DEVICE.create_buffer::<f32>(3 * (self.width() * self.height()) as usize);
         let staging_kernel = DEVICE.create_kernel::<fn(Tex2d<Radiance>)>(&track!(|texture| {
@@ -110,10 +105,10 @@ impl World {
 
         let mut staging_host = vec![0.0_f32; 3 * (self.width() * self.height()) as usize];
 
-        let file = File::create(path.as_ref()).unwrap();
-        let mut file = TiffEncoder::new(file).unwrap();
+        let file = File::create(path.as_ref()).map_err(AmidaError::io(path))?;
+        let mut file = TiffEncoder::new(file).map_err(AmidaError::tiff(path))?;
 
-        let mut save = |name: &str, texture: &Tex2d<Radiance>| {
+        let mut save = |name: &str, texture: &Tex2d<Radiance>| -> Result<()> {
             (
                 staging_kernel.dispatch_async([self.width(), self.height(), 1], texture),
                 staging_buffer.copy_to_async(&mut staging_host),
@@ -122,20 +117,19 @@ impl World {
                 .execute();
             let mut image = file
                 .new_image::<colortype::RGB32Float>(self.width(), self.height())
-                .unwrap();
+                .map_err(AmidaError::tiff(path))?;
             image
                 .encoder() // PageName
                 .write_tag(PAGENAME, name)
-                .unwrap();
-            image.write_data(&staging_host).unwrap();
+                .map_err(AmidaError::tiff(path))?;
+            image
+                .write_data(&staging_host)
+                .map_err(AmidaError::tiff(path))
         };
-        // Generally viewed in reverse.
-        save("display_opacity", &self.display_opacity);
-        save("display_diffuse", &self.display_diffuse);
-        save("display_emissive", &self.display_emissive);
-        save("opacity", &self.opacity);
-        save("diffuse", &self.diffuse);
-        save("emissive", &self.emissive);
+        for (name, texture) in LAYERS.into_iter().zip(self.layers()) {
+            save(name, texture)?;
+        }
+        Ok(())
     }
 
     pub fn write_pixel(&self, pos: Expr<Vec2<u32>>, material: Expr<LoadedMaterial>) {
@@ -152,34 +146,8 @@ impl World {
         path: impl AsRef<Path> + Copy,
         palette: Palette,
         materials: &[(String, LoadedMaterial)],
-    ) {
-        let palette = palette
-            .into_iter()
-            .map(|(color, brush)| {
-                let color = csscolorparser::parse(&color).unwrap().to_rgba8();
-                let brush = brush
-                    .as_slice()
-                    .iter()
-                    .map(|x| {
-                        materials
-                            .iter()
-                            .enumerate()
-                            .find(|(_, (name, _))| name == x)
-                            .map(|(i, _)| i as u32)
-                            .unwrap()
-                    })
-                    .collect::<Vec<_>>();
-                (color, brush)
-            })
-            .collect::<HashMap<_, _>>();
-
-        let image = ImageReader::open(path)
-            .unwrap()
-            .decode()
-            .unwrap()
-            .into_rgba8();
-
-        assert!(image.width() == self.width() && image.height() == self.height());
+    ) -> Result<()> {
+        let brushes = palette_materials(path, palette, materials, self.size)?;
 
         let staging_buffer =
             DEVICE.create_buffer::<f32>(3 * (self.width() * self.height()) as usize);
@@ -194,15 +162,9 @@ impl World {
         }));
 
         let load = |texture: &Tex2d<Radiance>, f: fn(&LoadedMaterial) -> Vec3<f32>| {
-            let data = image
-                .enumerate_pixels()
-                .flat_map(|(i, j, x)| {
-                    let brush = palette.get(&x.0).unwrap_or_else(|| {
-                        panic!("Color not found: {:?}", x.0);
-                    });
-                    let material = brush[pcg_host((i << 16) + j) as usize % brush.len()];
-                    <[f32; 3]>::from(f(&materials[material as usize].1))
-                })
+            let data = brushes
+                .iter()
+                .flat_map(|&material| <[f32; 3]>::from(f(&materials[material as usize].1)))
                 .collect::<Vec<_>>();
             staging_buffer.copy_from(&data);
             staging_kernel.dispatch([self.width(), self.height(), 1], texture);
@@ -213,5 +175,123 @@ impl World {
         load(&self.display_emissive, |x| x.display_emissive);
         load(&self.display_diffuse, |x| x.display_diffuse);
         load(&self.display_opacity, |x| x.display_opacity);
+        Ok(())
+    }
+}
+
+/// Reads the layers of a world file as linear RGB, in the order of [`LAYERS`].
+pub fn read_layers(path: impl AsRef<Path> + Copy, size: [u32; 2]) -> Result<Vec<Vec<FVec3>>> {
+    let path = path.as_ref().with_extension("tiff");
+    let file = File::open(&path).map_err(AmidaError::io(&path))?;
+    let mut file = TiffDecoder::new(file).map_err(AmidaError::tiff(&path))?;
+
+    let base_colortype = file.colortype().map_err(AmidaError::tiff(&path))?;
+
+    let mut layers = Vec::with_capacity(LAYERS.len());
+    for (i, name) in LAYERS.into_iter().enumerate() {
+        if i > 0 {
+            if !file.more_images() {
+                return Err(AmidaError::MissingLayer {
+                    path,
+                    layer: name.to_string(),
+                });
+            }
+            file.next_image().map_err(AmidaError::tiff(&path))?;
+        }
+
+        if let Ok(found) = file.get_tag_ascii_string(PAGENAME) {
+            if found != name {
+                return Err(AmidaError::WrongLayer {
+                    path,
+                    expected: name.to_string(),
+                    found,
+                });
+            }
+        }
+        let color_type = file.colortype().map_err(AmidaError::tiff(&path))?;
+        if color_type != base_colortype {
+            return Err(AmidaError::UnsupportedColorType {
+                path,
+                layer: Some(name.to_string()),
+                color_type,
+            });
+        }
+        let dimensions = file.dimensions().map_err(AmidaError::tiff(&path))?;
+        if dimensions != (size[0], size[1]) {
+            return Err(AmidaError::WrongDimensions {
+                path,
+                layer: Some(name.to_string()),
+                expected: (size[0], size[1]),
+                found: dimensions,
+            });
+        }
+        layers.push(read_f32_rgb(&mut file, &path, Some(name))?);
+    }
+    Ok(layers)
+}
+
+/// Picks a material for each pixel of the image, using the brush assigned to the pixel's color.
+pub fn palette_materials(
+    path: impl AsRef<Path> + Copy,
+    palette: Palette,
+    materials: &[(String, LoadedMaterial)],
+    size: [u32; 2],
+) -> Result<Vec<u32>> {
+    let palette = palette
+        .into_iter()
+        .map(|(color, brush)| -> Result<_> {
+            let rgba = csscolorparser::parse(&color)
+                .map_err(|_| AmidaError::InvalidColor {
+                    path: path.as_ref().to_path_buf(),
+                    color: color.clone(),
+                })?
+                .to_rgba8();
+            let brush = brush
+                .as_slice()
+                .iter()
+                .map(|x| {
+                    materials
+                        .iter()
+                        .position(|(name, _)| name == x)
+                        .map(|i| i as u32)
+                        .ok_or_else(|| AmidaError::UnknownMaterial {
+                            material: x.clone(),
+                            context: format!("palette color `{}`", color),
+                        })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if brush.is_empty() {
+                return Err(AmidaError::EmptyBrush {
+                    context: format!("palette color `{}`", color),
+                });
+            }
+            Ok((rgba, brush))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    let image = ImageReader::open(path)
+        .map_err(AmidaError::io(path))?
+        .decode()
+        .map_err(AmidaError::image(path))?
+        .into_rgba8();
+
+    if image.dimensions() != (size[0], size[1]) {
+        return Err(AmidaError::WrongDimensions {
+            path: path.as_ref().to_path_buf(),
+            layer: None,
+            expected: (size[0], size[1]),
+            found: image.dimensions(),
+        });
     }
+    image
+        .enumerate_pixels()
+        .map(|(i, j, x)| -> Result<_> {
+            let brush = palette.get(&x.0).ok_or_else(|| AmidaError::UnknownColor {
+                path: path.as_ref().to_path_buf(),
+                color: x.0,
+                position: (i, j),
+            })?;
+            Ok(brush[pcg((i << 16) + j) as usize % brush.len()])
+        })
+        .collect::<Result<Vec<_>>>()
 }
@@ -0,0 +1,140 @@
+//! Renders `world/room.png` with every preset in `settings/` and compares the
+//! result against the golden images in `tests/golden/`. Presets for a smaller world
+//! render the top left corner of it instead.
+//!
+//! Needs a CUDA device and the blessed goldens, so it is ignored by default and run with
+//! `cargo test --test golden -- --ignored`. Run with `AMIDA_BLESS=1` to (re)generate the goldens
+//! after an intended change in lighting. A missing golden is a failure otherwise.
+
+use std::path::{Path, PathBuf};
+
+use amida::compare::{error_heatmap, Metrics};
+use amida::render::{load_radiance, save_radiance, save_tonemapped};
+use amida::renderer::DisplayOptions;
+use amida::{load_materials, load_palette, load_settings, Renderer};
+
+const WORLD: &str = "world/room.png";
+const PALETTE: &str = "room-palette.ron";
+const ENVIRONMENT: &str = "env/default.tiff";
+const GOLDEN_DIR: &str = "tests/golden";
+const OUTPUT_DIR: &str = "target/golden";
+
+/// Maximum relative MSE of any channel before the render counts as a regression.
+/// The pipeline has no randomness between runs, so this only covers differences in float rounding between GPUs.
+const RELATIVE_MSE_TOLERANCE: f32 = 1e-4;
+
+fn presets() -> Vec<PathBuf> {
+    let mut presets = std::fs::read_dir("settings")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ron"))
+        .collect::<Vec<_>>();
+    presets.sort();
+    presets
+}
+
+#[test]
+#[ignore = "needs a CUDA device and the goldens in tests/golden/"]
+fn golden_images() {
+    let bless = std::env::var_os("AMIDA_BLESS").is_some();
+    let (width, height) = image::image_dimensions(WORLD).unwrap();
+    std::fs::create_dir_all(GOLDEN_DIR).unwrap();
+    std::fs::create_dir_all(OUTPUT_DIR).unwrap();
+
+    let mut failures = Vec::new();
+    for preset in presets() {
+        let name = preset.file_stem().unwrap().to_string_lossy().into_owned();
+        let settings = load_settings(preset.to_str().unwrap()).unwrap();
+        let world = if settings.world_size == [width, height] {
+            PathBuf::from(WORLD)
+        } else {
+            let [crop_width, crop_height] = settings.world_size;
+            assert!(
+                crop_width <= width && crop_height <= height,
+                "{}: world size {:?} is larger than {}",
+                name,
+                settings.world_size,
+                WORLD
+            );
+            let path = Path::new(OUTPUT_DIR).join(format!("{}-world.png", name));
+            image::open(WORLD)
+                .unwrap()
+                .crop_imm(0, 0, crop_width, crop_height)
+                .save(&path)
+                .unwrap();
+            path
+        };
+
+        let renderer = Renderer::new(&settings, load_materials(&settings.materials).unwrap());
+        renderer.load_environment(ENVIRONMENT).unwrap();
+        renderer
+            .load_world(
+                world.to_str().unwrap(),
+                Some(load_palette(PALETTE).unwrap()),
+            )
+            .unwrap();
+        for _ in 0..settings.render_frames.max(1) {
+            renderer
+                .commands(
+                    settings.num_bounces,
+                    &settings.display_merge,
+                    DisplayOptions {
+                        run_final: true,
+                        display_level: settings.display_level,
+                        raw_radiance: settings.raw_radiance,
+                        overlay: None,
+                    },
+                )
+                .execute();
+        }
+        let radiance = renderer.read_radiance();
+
+        let golden = Path::new(GOLDEN_DIR).join(&name);
+        if bless {
+            save_radiance(&radiance, settings.world_size, &golden).unwrap();
+            save_tonemapped(&radiance, settings.world_size, &golden).unwrap();
+            println!("{}: wrote golden image", name);
+            continue;
+        }
+        if !golden.with_extension("tiff").is_file() {
+            failures.push(format!(
+                "{}: no golden image at {}, run with AMIDA_BLESS=1 to write it",
+                name,
+                golden.with_extension("tiff").display()
+            ));
+            continue;
+        }
+
+        let (expected, size) = load_radiance(&golden).unwrap();
+        assert_eq!(
+            size, settings.world_size,
+            "{}: golden has the wrong size",
+            name
+        );
+        let metrics = Metrics::new(&expected, &radiance);
+        if metrics.relative_mse() > RELATIVE_MSE_TOLERANCE {
+            let output = Path::new(OUTPUT_DIR).join(&name);
+            save_radiance(&radiance, settings.world_size, &output).unwrap();
+            save_tonemapped(&radiance, settings.world_size, &output).unwrap();
+            let diff = output.with_file_name(format!("{}-error.png", name));
+            error_heatmap(&expected, &radiance, size, metrics.max_error())
+                .save(&diff)
+                .unwrap();
+            failures.push(format!(
+                "{}: relative mse {} exceeds {}, diff written to {}\n{}",
+                name,
+                metrics.relative_mse(),
+                RELATIVE_MSE_TOLERANCE,
+                diff.display(),
+                metrics
+            ));
+        } else {
+            println!("{}: ok (relative mse {})", name, metrics.relative_mse());
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "Lighting regressed:\n{}",
+        failures.join("\n")
+    );
+}